@@ -1,3 +1,5 @@
+use std::cell::Cell;
+use std::io;
 use std::mem;
 use std::time::Instant;
 
@@ -13,9 +15,76 @@ use mpi::traits::CommunicatorCollectives as _;
 pub struct Allreduce {
     #[arg(short, long, value_enum)]
     operation: crate::Operation,
+
+    /// Request rank-ordered reduction, and validate results bitwise against
+    /// summing ranks 0..n in order
+    #[arg(short, long)]
+    deterministic: bool,
+
+    /// Store contributions in the segment in a lossy format, and report the
+    /// error against the exact sum when validating
+    #[arg(short, long, value_enum)]
+    quantize: Option<Quantize>,
+
+    #[arg(skip)]
+    error: Cell<Error>,
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum Quantize {
+    Bf16,
+    Fp16,
+    Int8,
+}
+
+/// Accumulated error of quantized results against the exact sum.
+#[derive(Copy, Clone, Default)]
+struct Error {
+    count: usize,
+    max: f64,
+    squared: f64,
+    expected_squared: f64,
 }
 
 impl Allreduce {
+    /// Must be called before MPI is initialized.
+    pub fn configure(&self) {
+        if self.deterministic {
+            std::env::set_var("COLLECTIVE_ALLREDUCE_DETERMINISTIC", "1");
+        }
+        if let Some(quantize) = self.quantize {
+            let format = match quantize {
+                Quantize::Bf16 => "bf16",
+                Quantize::Fp16 => "fp16",
+                Quantize::Int8 => "int8",
+            };
+            std::env::set_var("COLLECTIVE_ALLREDUCE_QUANTIZE", format);
+        }
+    }
+
+    /// Print the error accumulated over all iterations at `size`, and reset it.
+    pub fn report(&self, output: &mut impl io::Write, size: usize) -> anyhow::Result<()> {
+        let Some(quantize) = self.quantize else {
+            return Ok(());
+        };
+
+        let error = self.error.take();
+        let rms = (error.squared / error.count as f64).sqrt();
+        let expected_rms = (error.expected_squared / error.count as f64).sqrt();
+
+        writeln!(
+            output,
+            "{:?} error at size {}: max {:e}, rms {:e} ({:.3e} of rms value) over {} elements",
+            quantize,
+            size,
+            error.max,
+            rms,
+            rms / expected_rms,
+            error.count,
+        )?;
+        Ok(())
+    }
+
     pub fn run(
         &self,
         world: &SystemCommunicator,
@@ -25,7 +94,7 @@ impl Allreduce {
         assert_eq!(size % mem::size_of::<f32>(), 0);
 
         let local = (0..size / mem::size_of::<f32>())
-            .map(|index| (world.rank() + index as i32) as f32)
+            .map(|index| self.value(world.rank(), index))
             .collect::<Vec<_>>();
 
         let mut global = vec![0.0f32; size / mem::size_of::<f32>()];
@@ -35,7 +104,45 @@ impl Allreduce {
         world.all_reduce_into(&local, &mut global[..], SystemOperation::sum());
         let end = Instant::now();
 
-        if validate {
+        if validate && self.quantize.is_some() {
+            let mut error = self.error.get();
+            for (index, actual) in global.into_iter().enumerate() {
+                let expected = (0..world.size())
+                    .map(|rank| self.value(rank, index) as f64)
+                    .sum::<f64>();
+
+                if !actual.is_finite() {
+                    return Err(anyhow!(
+                        "Expected value {:e} at index {}, but found {:e}",
+                        expected,
+                        index,
+                        actual,
+                    ));
+                }
+
+                let difference = (actual as f64 - expected).abs();
+                error.count += 1;
+                error.max = error.max.max(difference);
+                error.squared += difference * difference;
+                error.expected_squared += expected * expected;
+            }
+            self.error.set(error);
+        } else if validate && self.deterministic {
+            for (index, actual) in global.into_iter().enumerate() {
+                let expected = (0..world.size())
+                    .map(|rank| self.value(rank, index))
+                    .fold(0.0f32, |sum, value| sum + value);
+
+                if actual.to_bits() != expected.to_bits() {
+                    return Err(anyhow!(
+                        "Expected value {:e} at index {}, but found {:e}",
+                        expected,
+                        index,
+                        actual,
+                    ));
+                }
+            }
+        } else if validate {
             for (index, actual) in global.into_iter().enumerate() {
                 let expected =
                     // Contribution from each rank
@@ -59,4 +166,15 @@ impl Allreduce {
             .try_into()
             .context("Duration larger than 64 bits")
     }
+
+    /// Contribution of `rank` at `index`. Reciprocals are used in
+    /// deterministic mode, so that the sum depends on the order of addition,
+    /// and values spread over [-1, 1] when quantizing, like gradients.
+    fn value(&self, rank: i32, index: usize) -> f32 {
+        match (self.deterministic, self.quantize) {
+            (_, Some(_)) => ((rank as usize * 7919 + index * 104729) % 2001) as f32 / 1000.0 - 1.0,
+            (true, None) => 1.0 / (1 + rank + index as i32) as f32,
+            (false, None) => (rank + index as i32) as f32,
+        }
+    }
 }
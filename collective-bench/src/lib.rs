@@ -43,6 +43,10 @@ impl Benchmark {
             return Self::summarize();
         }
 
+        if let Benchmark::Allreduce(allreduce) = &self {
+            allreduce.configure();
+        }
+
         let universe = mpi::initialize().ok_or_else(|| anyhow!("Failed to initialize MPI"))?;
         let world = universe.world();
         let mut stdout = io::stdout().lock();
@@ -72,6 +76,12 @@ impl Benchmark {
                 writeln!(stdout)?;
                 stdout.flush()?;
             }
+
+            if let Benchmark::Allreduce(allreduce) = &self {
+                if configuration.validate && world.rank() == 0 {
+                    allreduce.report(&mut io::stderr(), *size)?;
+                }
+            }
         }
 
         Ok(())
@@ -0,0 +1,122 @@
+//! Throughput of each reduction kernel level on 1 MiB buffers of random data.
+//!
+//! ```text
+//! cargo bench -p collective --bench kernel
+//! ```
+
+#[allow(dead_code)]
+#[path = "../src/kernel.rs"]
+mod kernel;
+
+use std::hint;
+use std::time::Instant;
+
+use kernel::Level;
+
+const SIZE: usize = 1 << 20;
+const ITERATIONS: usize = 1000;
+
+/// Element made of random bits, finite for floats so sums stay finite.
+trait Random {
+    fn random(bits: u64) -> Self;
+}
+
+impl Random for f32 {
+    fn random(bits: u64) -> Self {
+        (bits as i32) as f32 / i32::MAX as f32
+    }
+}
+
+impl Random for f64 {
+    fn random(bits: u64) -> Self {
+        (bits as i64) as f64 / i64::MAX as f64
+    }
+}
+
+impl Random for i8 {
+    fn random(bits: u64) -> Self {
+        bits as i8
+    }
+}
+
+impl Random for i32 {
+    fn random(bits: u64) -> Self {
+        bits as i32
+    }
+}
+
+/// `len` random elements, from a deterministic xorshift started at `seed`.
+fn random<T: Random>(len: usize, seed: u64) -> Vec<T> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            T::random(state)
+        })
+        .collect()
+}
+
+macro_rules! bench {
+    ($name:ident, $type:ty) => {
+        bench!($name, $type, |level, shared, _compensation, other| {
+            kernel::$name::sum_with(level, shared, other)
+        })
+    };
+    ($name:ident, $type:ty, |$level:ident, $shared:ident, $compensation:ident, $other:ident| $sum:expr) => {{
+        let len = SIZE / std::mem::size_of::<$type>();
+        let mut shared = random::<$type>(len, 1);
+        let mut compensation = vec![<$type>::default(); len];
+        let other = random::<$type>(len, 2);
+        let mut baseline = None;
+
+        for level in [Level::Scalar, Level::Sse2, Level::Avx2, Level::Avx512]
+            .into_iter()
+            .filter(|level| *level <= Level::detect())
+        {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                let ($level, $shared, $compensation, $other) = (
+                    level,
+                    hint::black_box(&mut shared),
+                    &mut compensation,
+                    &other,
+                );
+                $sum;
+            }
+            let duration = (Instant::now() - start).as_secs_f64() / ITERATIONS as f64;
+            let baseline = *baseline.get_or_insert(duration);
+
+            println!(
+                "{}/{:?}: {:.2}us ({:.2} GiB/s, {:.2}x)",
+                stringify!($name),
+                level,
+                duration * 1e6,
+                SIZE as f64 / duration / (1u64 << 30) as f64,
+                baseline / duration,
+            );
+        }
+    }};
+}
+
+fn main() {
+    bench!(f32, f32);
+    bench!(f64, f64);
+    bench!(
+        compensated_f32,
+        f32,
+        |level, shared, compensation, other| {
+            kernel::compensated::f32::sum_with(level, shared, compensation, other)
+        }
+    );
+    bench!(
+        compensated_f64,
+        f64,
+        |level, shared, compensation, other| {
+            kernel::compensated::f64::sum_with(level, shared, compensation, other)
+        }
+    );
+    bench!(i8, i8);
+    bench!(i32, i32);
+}
@@ -0,0 +1,38 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Generate the C API header and a pkg-config file into `OUT_DIR`, which
+/// `make install` copies under its `PREFIX`, substituted for `@PREFIX@`.
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src").join("capi.rs"))
+        .generate()
+        .expect("Failed to generate C header")
+        .write_to_file(out_dir.join("collective.h"));
+
+    fs::write(
+        out_dir.join("collective.pc"),
+        format!(
+            "prefix=@PREFIX@\n\
+             libdir=${{prefix}}/lib\n\
+             includedir=${{prefix}}/include\n\
+             \n\
+             Name: collective\n\
+             Description: Shared memory collectives\n\
+             Version: {}\n\
+             Libs: -L${{libdir}} -lcollective\n\
+             Cflags: -I${{includedir}}\n",
+            env::var("CARGO_PKG_VERSION").unwrap(),
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=src/capi.rs");
+}
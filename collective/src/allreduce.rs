@@ -1,63 +1,169 @@
 use std::cmp;
 use std::env;
-use std::ffi;
+use std::fmt;
 use std::mem;
+use std::ops::Range;
 
-use mpi::traits::Communicator as _;
+use once_cell::sync::Lazy;
 
-use crate::barrier::Barrier;
+use crate::bakery::Bakery;
+use crate::checksum;
+use crate::coherence;
+use crate::copy;
 use crate::datatype::MpiType;
+use crate::group::Group;
+use crate::guard::Layout;
+use crate::local::Contiguous;
+use crate::local::Local;
+use crate::lock;
+use crate::lock::Lock;
+use crate::mcs::Mcs;
 use crate::metrics;
 use crate::mutex::Mutex;
+use crate::quantize;
+use crate::segment::Segment;
+use crate::ticket::Ticket;
+
+/// How contributions are combined.
+pub(crate) trait Reduction<T>: Copy {
+    /// Whether this sums, so zeroed memory is the identity, and compensation
+    /// applies. Otherwise, the first contribution to each element is copied
+    /// rather than reduced.
+    fn zeroed(&self) -> bool;
+
+    /// Element-wise `shared = other op shared`.
+    fn apply(&self, shared: &mut [T], other: &[T]);
+}
+
+/// `MPI_SUM`, reduced by `MpiType`.
+#[derive(Copy, Clone)]
+pub(crate) struct Sum;
+
+impl<T: MpiType> Reduction<T> for Sum {
+    fn zeroed(&self) -> bool {
+        true
+    }
+
+    fn apply(&self, shared: &mut [T], other: &[T]) {
+        T::sum_slice_mut(shared, other)
+    }
+}
+
+/// Reduce every element in rank order, regardless of arrival order, so
+/// floating point results are bitwise reproducible between runs. Replaces the
+/// single buffer algorithm with the multiple buffer one, which costs an extra
+/// copy of every contribution into the segment; compare
+/// `collective-bench allreduce --deterministic` against the default.
+static DETERMINISTIC: Lazy<bool> =
+    Lazy::new(|| env::var("COLLECTIVE_ALLREDUCE_DETERMINISTIC").is_ok());
+
+/// Sum floating point contributions with a compensation term per element,
+/// which is added to the result at the end. With the single buffer algorithm,
+/// compensation lives next to the shared accumulator; with the multiple buffer
+/// algorithm, it is local to the rank reducing each partition.
+static COMPENSATED: Lazy<bool> = Lazy::new(|| env::var("COLLECTIVE_ALLREDUCE_COMPENSATED").is_ok());
+
+/// Whether the selected algorithm reduces contributions in rank order,
+/// rather than arrival order.
+pub(crate) fn ordered() -> bool {
+    match env::var("COLLECTIVE_ALLREDUCE_ALGORITHM").as_deref() {
+        Ok("single") | Err(_) => *DETERMINISTIC,
+        Ok("multiple") => true,
+        Ok(algorithm) => panic!("Unknown allreduce algorithm: {}", algorithm),
+    }
+}
 
-#[no_mangle]
-pub unsafe extern "C" fn MPI_Allreduce(
-    buffer_send: *const ffi::c_void,
-    buffer_receive: *mut ffi::c_void,
-    count: ffi::c_int,
-    datatype: mpi::ffi::MPI_Datatype,
-    _: mpi::ffi::MPI_Op,
-    comm: mpi::ffi::MPI_Comm,
-) -> ffi::c_int {
-    metrics::reset();
-    metrics::time!(metrics::timers::TOTAL, {
-        if f32::matches(datatype) {
-            allreduce::<f32>(buffer_send, buffer_receive, count, comm);
-        } else if i32::matches(datatype) {
-            allreduce::<i32>(buffer_send, buffer_receive, count, comm);
-        } else if i8::matches(datatype) {
-            allreduce::<i8>(buffer_send, buffer_receive, count, comm);
+/// Reduce `buffer_send` across `group` into `buffer_receive`, with the
+/// algorithm selected by `COLLECTIVE_ALLREDUCE_ALGORITHM`, or quantized by
+/// `COLLECTIVE_ALLREDUCE_QUANTIZE` for f32 sums. Returns whether
+/// every checksum matched, which they do if disabled.
+pub(crate) unsafe fn reduce<T: MpiType + Copy, R: Reduction<T>>(
+    buffer_send: &[T],
+    buffer_receive: &mut [T],
+    reduction: R,
+    group: &Group,
+) -> bool {
+    match algorithm::<T, R>(&reduction) {
+        Algorithm::Quantized(format) => allreduce_quantized(
+            std::slice::from_raw_parts(buffer_send.as_ptr().cast(), buffer_send.len()),
+            std::slice::from_raw_parts_mut(
+                buffer_receive.as_mut_ptr().cast(),
+                buffer_receive.len(),
+            ),
+            format,
+            group,
+        ),
+        Algorithm::Single(lock::Algorithm::Ttas) => {
+            allreduce_single::<T, Mutex, R>(buffer_send, buffer_receive, reduction, group)
         }
-    });
-    metrics::dump();
-    mpi::ffi::MPI_SUCCESS as ffi::c_int
+        Algorithm::Single(lock::Algorithm::Bakery) => {
+            allreduce_single::<T, Bakery, R>(buffer_send, buffer_receive, reduction, group)
+        }
+        Algorithm::Single(lock::Algorithm::Ticket) => {
+            allreduce_single::<T, Ticket, R>(buffer_send, buffer_receive, reduction, group)
+        }
+        Algorithm::Single(lock::Algorithm::Mcs) => {
+            allreduce_single::<T, Mcs, R>(buffer_send, buffer_receive, reduction, group)
+        }
+        Algorithm::Multiple => {
+            allreduce_multiple(Contiguous(buffer_send, buffer_receive), reduction, group)
+        }
+    }
 }
 
-unsafe fn allreduce<T: MpiType + Copy>(
-    buffer_send: *const ffi::c_void,
-    buffer_receive: *mut ffi::c_void,
-    count: ffi::c_int,
-    comm: mpi::ffi::MPI_Comm,
-) {
-    let buffer_send = std::slice::from_raw_parts(buffer_send as *const T, count as usize);
-    let buffer_receive = std::slice::from_raw_parts_mut(buffer_receive as *mut T, count as usize);
-    let comm = crate::Communicator(comm);
+/// Bytes of slot `reduce` needs for `len` elements.
+pub(crate) fn required<T: MpiType, R: Reduction<T>>(
+    len: usize,
+    reduction: &R,
+    group: &Group,
+) -> usize {
+    let mut layout = Layout::measure();
+    match algorithm::<T, R>(reduction) {
+        Algorithm::Quantized(format) => {
+            carve_quantized(&mut layout, format, len, group.size as usize);
+        }
+        Algorithm::Single(algorithm) => {
+            let lock_size = match algorithm {
+                lock::Algorithm::Ttas => Mutex::size(group.size),
+                lock::Algorithm::Bakery => Bakery::size(group.size),
+                lock::Algorithm::Ticket => Ticket::size(group.size),
+                lock::Algorithm::Mcs => Mcs::size(group.size),
+            };
+            carve_single::<T, R>(&mut layout, len * mem::size_of::<T>(), lock_size, reduction);
+        }
+        Algorithm::Multiple => return required_multiple::<T>(len, group),
+    }
+    layout.size()
+}
 
-    let algorithm = env::var("COLLECTIVE_ALLREDUCE_ALGORITHM");
+/// Bytes of slot `allreduce_multiple` needs for `len` elements.
+pub(crate) fn required_multiple<T: MpiType>(len: usize, group: &Group) -> usize {
+    let mut layout = Layout::measure();
+    carve_multiple::<T>(&mut layout, len, group.size as usize);
+    layout.size()
+}
 
-    match algorithm.as_deref() {
-        Ok("single") | Err(_) => allreduce_single(buffer_send, buffer_receive, comm),
-        Ok("multiple") => allreduce_multiple(buffer_send, buffer_receive, comm),
-        Ok(algorithm) => panic!("Unknown allreduce algorithm: {}", algorithm),
+/// Algorithms `reduce` selects between.
+enum Algorithm {
+    Quantized(quantize::Format),
+    Single(lock::Algorithm),
+    Multiple,
+}
+
+fn algorithm<T: MpiType, R: Reduction<T>>(reduction: &R) -> Algorithm {
+    match (*quantize::FORMAT, T::QUANTIZABLE && reduction.zeroed()) {
+        (Some(format), true) => Algorithm::Quantized(format),
+        _ if ordered() => Algorithm::Multiple,
+        _ => Algorithm::Single(*lock::ALGORITHM),
     }
 }
 
-unsafe fn allreduce_single<T: MpiType + Copy>(
+unsafe fn allreduce_single<'pci, T: MpiType + Copy, L: Lock<'pci>, R: Reduction<T>>(
     buffer_send: &[T],
     buffer_receive: &mut [T],
-    comm: crate::Communicator,
-) {
-    // | Barrier             |
+    reduction: R,
+    group: &Group,
+) -> bool {
     // | Region 0 Lock       |
     // | Region 1 Lock       |
     // | ...                 |
@@ -72,41 +178,70 @@ unsafe fn allreduce_single<T: MpiType + Copy>(
     let data_size = buffer_send.len() * mem::size_of::<T>();
     let region_size = crate::PAGE_SIZE;
     let region_count = (data_size + region_size - 1) / region_size;
-    let region_offset = comm.rank() as usize * (region_count / comm.size() as usize);
+    let region_offset = group.rank as usize * (region_count / group.size as usize);
 
-    let mut pci_map = crate::PCI_MAP.lock().unwrap();
+    let mut pci_map = crate::pci().lock();
+    let (segment, slot) = Segment::split(&mut pci_map, group.rank, group.size);
+    let barrier = segment.barrier();
 
     // Partition shared memory into disjoint areas
-    let (barrier, locks, buffer_shared) = {
-        let (barrier, remainder) = pci_map.split_at_mut(Barrier::SIZE);
-        let (locks, remainder) = remainder.split_at_mut(Mutex::SIZE * region_count);
-
-        let offset = remainder.as_ptr().align_offset(crate::PAGE_SIZE);
+    let mut layout = Layout::new(slot);
+    let (locks, buffer_shared, mut compensation, mut reduced, mut checksums) = {
+        let Single {
+            locks,
+            data,
+            mut compensation,
+            mut reduced,
+            mut checksums,
+        } = carve_single::<T, R>(&mut layout, data_size, L::size(group.size), &reduction);
 
         // Zero memory
-        if comm.rank() == 0 {
+        if group.rank == 0 {
+            segment.reserve();
             metrics::time!(metrics::timers::ZERO, {
                 locks.fill(0);
-                remainder[offset..][..data_size].fill(0);
+                data.fill(0);
+                coherence::flush(locks);
+                coherence::flush(data);
+                if let Some(compensation) = &mut compensation {
+                    compensation.fill(0);
+                    coherence::flush(compensation);
+                }
+                if let Some(reduced) = &mut reduced {
+                    reduced.fill(0);
+                    coherence::flush(reduced);
+                }
             });
+            if let Some(checksums) = &mut checksums {
+                checksum::compute(data, checksums);
+            }
+            layout.initialize();
         }
 
-        let (prefix, data, suffix) = remainder[offset..][..data_size].align_to_mut::<T>();
+        let (prefix, data, suffix) = data.align_to_mut::<T>();
 
         assert!(prefix.is_empty());
         assert!(suffix.is_empty());
 
-        let barrier = unsafe { Barrier::new(barrier.as_ptr()) };
+        let compensation = compensation.map(|compensation| {
+            let (prefix, compensation, suffix) = compensation.align_to_mut::<T>();
+            assert!(prefix.is_empty());
+            assert!(suffix.is_empty());
+            compensation
+        });
+
         let locks = (0..region_count)
-            .map(|region| region * Mutex::SIZE)
+            .map(|region| region * L::size(group.size))
             .map(|offset| locks[offset..].as_ptr())
-            .map(|address| Mutex::new(address))
+            .map(|address| L::new(address, group.rank, group.size))
             .collect::<Vec<_>>();
 
-        (barrier, locks, data)
+        (locks, data, compensation, reduced, checksums)
     };
 
-    barrier.wait(comm.rank(), comm.size());
+    barrier.wait(group.rank, group.size);
+
+    let mut valid = true;
 
     // Start at different offsets
     for region in (0..region_count)
@@ -122,54 +257,123 @@ unsafe fn allreduce_single<T: MpiType + Copy>(
 
         locks[region].lock();
         metrics::time!(metrics::timers::COMPUTE, {
-            buffer_shared[offset..][..count]
-                .iter_mut()
-                .zip(&buffer_send[offset..][..count])
-                .for_each(|(shared, send)| shared.sum_mut(send));
+            let shared = &mut buffer_shared[offset..][..count];
+            coherence::invalidate(shared);
+            if let Some(checksums) = &checksums {
+                valid &= checksum::verify("allreduce partial sum", shared, checksums, region);
+            }
+            let other = &buffer_send[offset..][..count];
+            match (&mut compensation, &mut reduced) {
+                (Some(compensation), _) => {
+                    let compensation = &mut compensation[offset..][..count];
+                    coherence::invalidate(compensation);
+                    T::sum_slice_compensated(shared, compensation, other);
+                    coherence::flush(compensation);
+                }
+                (None, Some(reduced)) => {
+                    let reduced = &mut reduced[region..][..1];
+                    coherence::invalidate(reduced);
+                    match reduced[0] {
+                        0 => shared.copy_from_slice(other),
+                        _ => reduction.apply(shared, other),
+                    }
+                    reduced[0] = 1;
+                    coherence::flush(reduced);
+                }
+                (None, None) => reduction.apply(shared, other),
+            }
+            coherence::flush(shared);
+            if let Some(checksums) = &mut checksums {
+                checksum::compute(shared, &mut checksums[region..][..1]);
+            }
         });
         locks[region].unlock();
     }
 
     // Wait for all processes to finish writes
-    barrier.wait(comm.rank(), comm.size());
+    barrier.wait(group.rank, group.size);
 
     metrics::time!(metrics::timers::COPY, {
-        buffer_receive.copy_from_slice(buffer_shared);
+        copy::read(buffer_receive, buffer_shared);
     });
-}
+    if let Some(checksums) = &checksums {
+        valid &= checksum::verify("allreduce result", buffer_receive, checksums, 0);
+    }
+    if let Some(compensation) = &compensation {
+        coherence::invalidate(compensation);
+        T::sum_slice_mut(buffer_receive, compensation);
+    }
 
-unsafe fn allreduce_multiple<T: MpiType + Copy>(
-    buffer_send: &[T],
-    buffer_receive: &mut [T],
-    comm: crate::Communicator,
-) {
-    let comm_rank = comm.rank() as usize;
-    let comm_size = comm.size() as usize;
+    layout.verify();
+    segment.complete();
+    valid
+}
 
-    let byte_size = buffer_send.len() * mem::size_of::<T>();
-    let byte_size_aligned = align(byte_size);
+/// Regions of `allreduce_single`, with data and compensation as bytes.
+struct Single<'pci> {
+    locks: &'pci mut [u8],
+    data: &'pci mut [u8],
+    compensation: Option<&'pci mut [u8]>,
+    /// Whether each region holds a contribution yet, for operators without
+    /// an identity to zero it with.
+    reduced: Option<&'pci mut [u32]>,
+    /// One checksum per region, of its running sum.
+    checksums: Option<&'pci mut [u32]>,
+}
 
-    let data_size = buffer_send.len();
-    let data_size_aligned = byte_size_aligned / mem::size_of::<T>();
+fn carve_single<'pci, T: MpiType, R: Reduction<T>>(
+    layout: &mut Layout<'pci>,
+    data_size: usize,
+    lock_size: usize,
+    reduction: &R,
+) -> Single<'pci> {
+    let region_count = (data_size + crate::PAGE_SIZE - 1) / crate::PAGE_SIZE;
+    Single {
+        locks: layout.carve("locks", lock_size * region_count, 1),
+        data: layout.carve("data", data_size, crate::PAGE_SIZE),
+        compensation: (*COMPENSATED && T::INEXACT && reduction.zeroed())
+            .then(|| layout.carve("compensation", data_size, crate::PAGE_SIZE)),
+        reduced: (!reduction.zeroed()).then(|| layout.carve_as::<u32>("reduced", region_count)),
+        checksums: checksum::enabled().then(|| layout.carve_as::<u32>("checksums", region_count)),
+    }
+}
 
-    let mut pci_map = crate::PCI_MAP.lock().unwrap();
+/// Stages every rank's contribution, then each rank sums its partition from
+/// rank 0 to rank n - 1, so results do not depend on arrival order. Operators
+/// without an identity fold from rank n - 1 down instead, starting from a copy,
+/// so that non-commutative ones see `rank 0 op (rank 1 op (...))`.
+pub(crate) unsafe fn allreduce_multiple<T: MpiType + Copy, R: Reduction<T>>(
+    mut local: impl Local<T>,
+    reduction: R,
+    group: &Group,
+) -> bool {
+    let comm_rank = group.rank as usize;
+    let comm_size = group.size as usize;
 
-    let (barrier, remainder) = pci_map.split_at_mut(Barrier::SIZE);
-    let barrier = Barrier::new(barrier.as_ptr());
-    let offset = remainder.as_ptr().align_offset(crate::PAGE_SIZE);
+    let byte_size = local.len() * mem::size_of::<T>();
+    let data_size = local.len();
 
-    let (buffer_shared_send_all, remainder) =
-        remainder[offset..].split_at_mut(byte_size_aligned * comm_size);
+    let mut pci_map = crate::pci().lock();
+    let (segment, slot) = Segment::split(&mut pci_map, group.rank, group.size);
+    let barrier = segment.barrier();
 
-    let (prefix, buffer_shared_send_all, suffix) = buffer_shared_send_all.align_to_mut::<T>();
-    assert_eq!(prefix.len(), 0);
-    assert_eq!(suffix.len(), 0);
+    // Every rank writes into the slot before the first barrier
+    segment.reserve();
 
-    let buffer_shared = &mut remainder[..byte_size];
-    if comm.rank() == 0 {
+    // Partition shared memory into disjoint areas
+    let mut layout = Layout::new(slot);
+    let Multiple {
+        send_all: mut buffer_shared_send_all,
+        shared: buffer_shared,
+        mut checksums,
+    } = carve_multiple::<T>(&mut layout, data_size, comm_size);
+
+    if group.rank == 0 {
         metrics::time!(metrics::timers::ZERO, {
             buffer_shared.fill(0);
+            coherence::flush(buffer_shared);
         });
+        layout.initialize();
     }
 
     let (prefix, buffer_shared, suffix) = buffer_shared.align_to_mut::<T>();
@@ -177,39 +381,301 @@ unsafe fn allreduce_multiple<T: MpiType + Copy>(
     assert_eq!(suffix.len(), 0);
 
     metrics::time!(metrics::timers::COPY, {
-        buffer_shared_send_all[data_size_aligned * comm_rank..][..data_size]
-            .copy_from_slice(buffer_send);
+        let checksums = checksums
+            .as_mut()
+            .map(|(send_all, _)| &mut *send_all[comm_rank]);
+        local.write(buffer_shared_send_all[comm_rank], checksums);
     });
 
     barrier.wait(comm_rank as i32, comm_size as i32);
 
     let partition = cmp::max(crate::PAGE_SIZE, align(byte_size / comm_size)) / mem::size_of::<T>();
 
+    // Partitions are page aligned, so start on a checksum boundary
+    let chunk = partition * comm_rank * mem::size_of::<T>() / checksum::CHUNK_SIZE;
+
+    let mut valid = true;
+
     if partition * comm_rank < data_size {
         metrics::time!(metrics::timers::COMPUTE, {
-            (0..comm_size)
-                .map(|rank| {
-                    let send = &buffer_shared_send_all[data_size_aligned * rank..][..data_size]
-                        [partition * comm_rank..];
+            let shared = &mut buffer_shared[partition * comm_rank..];
+            let len = cmp::min(shared.len(), partition);
+            let shared = &mut shared[..len];
+
+            coherence::invalidate(shared);
+            let mut compensation =
+                (*COMPENSATED && T::INEXACT && reduction.zeroed()).then(|| vec![T::default(); len]);
+            let ranks = match reduction.zeroed() {
+                true => (0..comm_size).collect::<Vec<_>>(),
+                false => (0..comm_size).rev().collect::<Vec<_>>(),
+            };
+            ranks
+                .into_iter()
+                .enumerate()
+                .map(|(index, rank)| {
+                    let send = &buffer_shared_send_all[rank][partition * comm_rank..];
                     let len = cmp::min(send.len(), partition);
-                    &send[..len]
+                    (index, rank, &send[..len])
                 })
-                .for_each(|buffer_send| {
-                    let shared = &mut buffer_shared[partition * comm_rank..];
-                    let len = cmp::min(shared.len(), partition);
-                    shared[..len]
-                        .iter_mut()
-                        .zip(buffer_send)
-                        .for_each(|(shared, send)| shared.sum_mut(send));
+                .for_each(|(index, rank, buffer_send)| {
+                    coherence::invalidate(buffer_send);
+                    if let Some((send_all, _)) = &checksums {
+                        valid &= checksum::verify(
+                            "allreduce contribution",
+                            buffer_send,
+                            send_all[rank],
+                            chunk,
+                        );
+                    }
+                    match (&mut compensation, reduction) {
+                        (Some(compensation), _) => {
+                            T::sum_slice_compensated(shared, compensation, buffer_send)
+                        }
+                        (None, _) if index == 0 && !reduction.zeroed() => {
+                            shared.copy_from_slice(buffer_send)
+                        }
+                        (None, _) => reduction.apply(shared, buffer_send),
+                    }
                 });
+            if let Some(compensation) = &compensation {
+                T::sum_slice_mut(shared, compensation);
+            }
+            coherence::flush(shared);
+            if let Some((_, checksums)) = &mut checksums {
+                checksum::compute(shared, &mut checksums[chunk..]);
+            }
+        });
+    }
+
+    barrier.wait(group.rank, group.size);
+
+    valid &= metrics::time!(metrics::timers::COPY, {
+        local.read(
+            "allreduce result",
+            buffer_shared,
+            checksums.as_ref().map(|(_, shared)| &**shared),
+        )
+    });
+
+    layout.verify();
+    segment.complete();
+    valid
+}
+
+/// Regions of `allreduce_multiple`: every rank's contribution, the result
+/// as bytes, and checksums of each.
+struct Multiple<'pci, T> {
+    send_all: Vec<&'pci mut [T]>,
+    shared: &'pci mut [u8],
+    checksums: Option<(Vec<&'pci mut [u32]>, &'pci mut [u32])>,
+}
+
+fn carve_multiple<'pci, T>(
+    layout: &mut Layout<'pci>,
+    len: usize,
+    ranks: usize,
+) -> Multiple<'pci, T> {
+    let byte_size = len * mem::size_of::<T>();
+    let send_all = (0..ranks)
+        .map(|rank| {
+            let send = layout.carve(format_args!("send {}", rank), byte_size, crate::PAGE_SIZE);
+            let (prefix, send, suffix) = unsafe { send.align_to_mut::<T>() };
+            assert_eq!(prefix.len(), 0);
+            assert_eq!(suffix.len(), 0);
+            send
+        })
+        .collect::<Vec<_>>();
+
+    let shared = layout.carve("shared", byte_size, crate::PAGE_SIZE);
+
+    let checksums = checksum::enabled().then(|| {
+        let chunks = checksum::chunks::<T>(len);
+        let send_all = (0..ranks)
+            .map(|rank| layout.carve_as::<u32>(format_args!("send {} checksums", rank), chunks))
+            .collect::<Vec<_>>();
+        let shared = layout.carve_as::<u32>("shared checksums", chunks);
+        (send_all, shared)
+    });
+
+    Multiple {
+        send_all,
+        shared,
+        checksums,
+    }
+}
+
+/// Like `allreduce_multiple`, but with contributions and results stored in
+/// the slot in a lossy `format`, and summed in f32. Only applies to sums.
+/// Returns whether every checksum matched, which they do if disabled.
+unsafe fn allreduce_quantized(
+    buffer_send: &[f32],
+    buffer_receive: &mut [f32],
+    format: quantize::Format,
+    group: &Group,
+) -> bool {
+    let count = buffer_send.len();
+
+    let comm_rank = group.rank as usize;
+    let comm_size = group.size as usize;
+
+    let partitions = Partitions::new(format, count, comm_size);
+
+    let mut pci_map = crate::pci().lock();
+    let (segment, slot) = Segment::split(&mut pci_map, group.rank, group.size);
+    let barrier = segment.barrier();
+
+    // Every rank writes into the slot before the first barrier
+    segment.reserve();
+
+    let mut layout = Layout::new(slot);
+    let (mut buffer_shared_send_all, mut shared) =
+        carve_quantized(&mut layout, format, count, comm_size);
+
+    if group.rank == 0 {
+        layout.initialize();
+    }
+
+    metrics::time!(metrics::timers::COPY, {
+        let send = &mut buffer_shared_send_all[comm_rank];
+        format.encode(buffer_send, send.values, send.scales);
+        coherence::flush(send.values);
+        coherence::flush(send.scales);
+        for index in 0..comm_size {
+            partitions.compute(send, index);
+        }
+    });
+
+    barrier.wait(group.rank, group.size);
+
+    let mut valid = true;
+    let (values, scales) = partitions.ranges[comm_rank].clone();
+    let len = values.len() / format.size();
+
+    if len > 0 {
+        metrics::time!(metrics::timers::COMPUTE, {
+            let mut sum = vec![0.0; len];
+            for send in &buffer_shared_send_all {
+                let (send_values, send_scales) =
+                    (&send.values[values.clone()], &send.scales[scales.clone()]);
+                coherence::invalidate(send_values);
+                coherence::invalidate(send_scales);
+                valid &= partitions.verify("allreduce contribution", send, comm_rank);
+                format.accumulate(send_values, send_scales, &mut sum);
+            }
+
+            let shared_values = &mut shared.values[values];
+            let shared_scales = &mut shared.scales[scales];
+            format.encode(&sum, shared_values, shared_scales);
+            coherence::flush(shared_values);
+            coherence::flush(shared_scales);
+            partitions.compute(&mut shared, comm_rank);
         });
     }
 
-    barrier.wait(comm.rank(), comm.size());
+    barrier.wait(group.rank, group.size);
 
     metrics::time!(metrics::timers::COPY, {
-        buffer_receive.copy_from_slice(buffer_shared);
+        coherence::invalidate(shared.values);
+        coherence::invalidate(shared.scales);
+        for index in 0..comm_size {
+            valid &= partitions.verify("allreduce result", &shared, index);
+        }
+        format.decode(shared.values, shared.scales, buffer_receive);
     });
+
+    layout.verify();
+    segment.complete();
+    valid
+}
+
+/// Values and scales of each rank's contribution, and of the result.
+fn carve_quantized<'pci>(
+    layout: &mut Layout<'pci>,
+    format: quantize::Format,
+    count: usize,
+    ranks: usize,
+) -> (Vec<Quantized<'pci>>, Quantized<'pci>) {
+    let stride = Partitions::new(format, count, ranks).stride;
+    let mut carve = |name: fmt::Arguments| Quantized {
+        values: layout.carve(
+            format_args!("{} values", name),
+            count * format.size(),
+            crate::PAGE_SIZE,
+        ),
+        scales: layout.carve_as::<f32>(format_args!("{} scales", name), format.scales(count)),
+        checksums: checksum::enabled()
+            .then(|| layout.carve_as::<u32>(format_args!("{} checksums", name), ranks * stride)),
+    };
+    let send_all = (0..ranks)
+        .map(|rank| carve(format_args!("send {}", rank)))
+        .collect::<Vec<_>>();
+    let shared = carve(format_args!("shared"));
+    (send_all, shared)
+}
+
+/// Encoded values and scales in the slot, and checksums of each partition.
+struct Quantized<'pci> {
+    values: &'pci mut [u8],
+    scales: &'pci mut [f32],
+    checksums: Option<&'pci mut [u32]>,
+}
+
+/// Values and scales reduced by each rank. Scales of a partition need not
+/// start on a checksum chunk, so each partition is checksummed on its own,
+/// values then scales, `stride` checksums apart.
+struct Partitions {
+    ranges: Vec<(Range<usize>, Range<usize>)>,
+    values_chunks: usize,
+    stride: usize,
+}
+
+impl Partitions {
+    fn new(format: quantize::Format, count: usize, ranks: usize) -> Self {
+        // Whole pages of every format's values, and whole cache lines of scales
+        let partition = cmp::max(crate::PAGE_SIZE, align(count / ranks));
+        let ranges = (0..ranks)
+            .map(|rank| {
+                let start = cmp::min(partition * rank, count);
+                let len = cmp::min(partition, count - start);
+                (
+                    start * format.size()..(start + len) * format.size(),
+                    format.scales(start)..format.scales(start) + format.scales(len),
+                )
+            })
+            .collect();
+
+        let values_chunks = checksum::chunks::<u8>(partition * format.size());
+        Partitions {
+            ranges,
+            values_chunks,
+            stride: values_chunks + checksum::chunks::<f32>(format.scales(partition)),
+        }
+    }
+
+    fn compute(&self, buffer: &mut Quantized, index: usize) {
+        let Some(checksums) = &mut buffer.checksums else {
+            return;
+        };
+
+        let (values, scales) = &self.ranges[index];
+        let (for_values, for_scales) =
+            checksums[index * self.stride..][..self.stride].split_at_mut(self.values_chunks);
+        checksum::compute(&buffer.values[values.clone()], for_values);
+        checksum::compute(&buffer.scales[scales.clone()], for_scales);
+    }
+
+    fn verify(&self, name: &str, buffer: &Quantized, index: usize) -> bool {
+        let Some(checksums) = &buffer.checksums else {
+            return true;
+        };
+
+        let (values, scales) = &self.ranges[index];
+        let (for_values, for_scales) =
+            checksums[index * self.stride..][..self.stride].split_at(self.values_chunks);
+        let values = checksum::verify(name, &buffer.values[values.clone()], for_values, 0);
+        let scales = checksum::verify(name, &buffer.scales[scales.clone()], for_scales, 0);
+        values && scales
+    }
 }
 
 fn align(value: usize) -> usize {
@@ -0,0 +1,125 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::coherence;
+use crate::lock::Backoff;
+use crate::lock::Lock;
+use crate::metrics;
+use crate::wait;
+use crate::wait::Waiter;
+
+/// Lamport's bakery lock using only single-writer flags.
+///
+/// Each rank owns a cache line holding its `choosing` flag and ticket
+/// `number`, and takes a ticket one larger than any it observes. Ties are
+/// broken by rank. Store-load ordering comes from `fence` rather than locked
+/// instructions, so no atomic read-modify-write is needed.
+///
+/// | Rank 0 Choosing, Number |
+/// | Rank 1 Choosing, Number |
+/// | ...                     |
+pub struct Bakery<'pci> {
+    lines: &'pci [AtomicU64],
+    rank: usize,
+    total: usize,
+}
+
+impl<'pci> Bakery<'pci> {
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    fn choosing(&self, rank: usize) -> &'pci AtomicU64 {
+        &self.lines[rank * Self::WORDS]
+    }
+
+    fn number(&self, rank: usize) -> &'pci AtomicU64 {
+        &self.lines[rank * Self::WORDS + 1]
+    }
+}
+
+impl<'pci> Lock<'pci> for Bakery<'pci> {
+    fn size(total: ffi::c_int) -> usize {
+        total as usize * crate::CACHE_LINE_SIZE
+    }
+
+    unsafe fn new(address: *const u8, rank: ffi::c_int, total: ffi::c_int) -> Self {
+        Self {
+            lines: slice::from_raw_parts(address.cast(), total as usize * Self::WORDS),
+            rank: rank as usize,
+            total: total as usize,
+        }
+    }
+
+    fn lock(&self) {
+        coherence::store(self.choosing(self.rank), 1, Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+
+        let number = (0..self.total)
+            .map(|rank| coherence::load(self.number(rank), Ordering::Relaxed))
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        coherence::store(self.number(self.rank), number, Ordering::Relaxed);
+        coherence::store(self.choosing(self.rank), 0, Ordering::Release);
+        atomic::fence(Ordering::SeqCst);
+
+        let mut contended = false;
+        let mut backoff = Backoff::new();
+        let mut waiter = Waiter::new();
+
+        metrics::time!(metrics::timers::MUTEX, {
+            for rank in (0..self.total).filter(|rank| *rank != self.rank) {
+                // Spin waiting for rank to finish choosing its number
+                loop {
+                    let choosing = coherence::load(self.choosing(rank), Ordering::Acquire);
+                    if choosing == 0 {
+                        break;
+                    }
+                    contended = true;
+                    backoff.spin();
+                    waiter.wait(|| wait::Stall {
+                        rank: self.rank as ffi::c_int,
+                        word: "bakery lock choosing",
+                        condition: "==",
+                        expected: 0,
+                        observed: choosing,
+                        missing: Some(vec![rank as ffi::c_int]),
+                    });
+                }
+
+                // Spin waiting for ranks with earlier tickets
+                loop {
+                    let other = coherence::load(self.number(rank), Ordering::Acquire);
+                    if other == 0 || (other, rank) > (number, self.rank) {
+                        break;
+                    }
+                    contended = true;
+                    backoff.spin();
+                    waiter.wait(|| wait::Stall {
+                        rank: self.rank as ffi::c_int,
+                        word: "bakery lock number",
+                        condition: "==",
+                        expected: 0,
+                        observed: other,
+                        missing: Some(vec![rank as ffi::c_int]),
+                    });
+                }
+            }
+        });
+
+        if contended {
+            metrics::increment!(metrics::counters::MUTEX_CONTENDED);
+        } else {
+            metrics::increment!(metrics::counters::MUTEX_UNCONTENDED);
+        }
+    }
+
+    fn unlock(&self) {
+        coherence::store(self.number(self.rank), 0, Ordering::Release);
+        wait::wake_all(self.rank as ffi::c_int, self.total as ffi::c_int);
+    }
+}
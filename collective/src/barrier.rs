@@ -1,63 +1,164 @@
+use std::env;
 use std::ffi;
+use std::mem;
+use std::slice;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 
+use once_cell::sync::Lazy;
+
+use crate::coherence;
+use crate::dissemination::Dissemination;
 use crate::metrics;
+use crate::tournament::Tournament;
+use crate::tree::Tree;
+use crate::wait;
+
+#[derive(Copy, Clone)]
+enum Algorithm {
+    Central,
+    Dissemination,
+    Tournament,
+    Tree,
+}
+
+static ALGORITHM: Lazy<Algorithm> =
+    Lazy::new(
+        || match env::var("COLLECTIVE_BARRIER_ALGORITHM").as_deref() {
+            Ok("central") | Err(_) => Algorithm::Central,
+            Ok("dissemination") => Algorithm::Dissemination,
+            Ok("tournament") => Algorithm::Tournament,
+            Ok("tree") => Algorithm::Tree,
+            Ok(algorithm) => panic!("Unknown barrier algorithm: {}", algorithm),
+        },
+    );
 
-pub struct Barrier<'pci>(&'pci AtomicU64);
+/// Barrier selected by `COLLECTIVE_BARRIER_ALGORITHM`.
+///
+/// The central and combining tree barriers rely on `fetch_add`, while the
+/// dissemination and tournament barriers only use single-writer flags, for
+/// devices without cross-host atomics. All but the central barrier spread
+/// ranks across separate cache lines.
+pub enum Barrier<'pci> {
+    Central(Central<'pci>),
+    Dissemination(Dissemination<'pci>),
+    Tournament(Tournament<'pci>),
+    Tree(Tree<'pci>),
+}
 
 impl<'pci> Barrier<'pci> {
-    pub const SIZE: usize = crate::CACHE_LINE_SIZE;
+    pub const SIZE: usize = 8 * crate::PAGE_SIZE;
 
-    /// Requires first `BARRIER_SIZE` bytes to be zero-initialized.
+    /// Requires first `SIZE` bytes to be zero-initialized.
     pub unsafe fn new(address: *const u8) -> Self {
-        Self(&*address.cast())
+        match *ALGORITHM {
+            Algorithm::Central => Barrier::Central(Central::new(address)),
+            Algorithm::Dissemination => Barrier::Dissemination(Dissemination::new(address)),
+            Algorithm::Tournament => Barrier::Tournament(Tournament::new(address)),
+            Algorithm::Tree => Barrier::Tree(Tree::new(address)),
+        }
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn name() -> &'static str {
+        match *ALGORITHM {
+            Algorithm::Central => "central",
+            Algorithm::Dissemination => "dissemination",
+            Algorithm::Tournament => "tournament",
+            Algorithm::Tree => "tree",
+        }
+    }
+
+    pub fn wait(&self, rank: ffi::c_int, total: ffi::c_int) {
+        metrics::increment!(metrics::counters::BARRIER);
+        match self {
+            Barrier::Central(barrier) => barrier.wait(rank, total),
+            Barrier::Dissemination(barrier) => barrier.wait(rank, total),
+            Barrier::Tournament(barrier) => barrier.wait(rank, total),
+            Barrier::Tree(barrier) => barrier.wait(rank, total),
+        }
+    }
+}
+
+/// Counting barrier, where every rank increments one shared counter.
+///
+/// | Counter             |
+/// | ...                 |
+/// | Arrivals            |
+pub struct Central<'pci> {
+    counter: &'pci AtomicU64,
+    arrivals: Arrivals<'pci>,
+}
+
+impl<'pci> Central<'pci> {
+    /// Requires first `Barrier::SIZE` bytes to be zero-initialized.
+    unsafe fn new(address: *const u8) -> Self {
+        Self {
+            counter: &*address.cast(),
+            arrivals: Arrivals::new(address),
+        }
     }
 
-    #[cfg_attr(not(feature = "interrupts"), allow(unused_variables))]
-    pub fn wait(&self, exclude: ffi::c_int, total: ffi::c_int) {
+    pub fn wait(&self, rank: ffi::c_int, total: ffi::c_int) {
         static EPOCH: AtomicU64 = AtomicU64::new(0);
 
-        let total = total as u64;
         let epoch_before = EPOCH.load(Ordering::Acquire);
-        let epoch_after = epoch_before + total;
+        let epoch_after = epoch_before + total as u64;
 
-        if self.0.fetch_add(1, Ordering::AcqRel) + 1 < epoch_after {
+        self.arrivals.arrive(rank, epoch_after);
+        if coherence::fetch_add(self.counter, 1, Ordering::AcqRel) + 1 < epoch_after {
             metrics::time!(metrics::timers::BARRIER, {
-                #[cfg(feature = "interrupts")]
-                unsafe {
-                    use std::os::fd::AsRawFd as _;
-                    assert_eq!(
-                        libc::read(crate::PCI_FILE.as_raw_fd(), std::ptr::null_mut(), 0),
-                        0,
-                    );
-                }
-
-                // Spin waiting for all processes to reach barrier
-                #[cfg(not(feature = "interrupts"))]
-                while self.0.load(Ordering::Acquire) < epoch_after {}
+                // Wait for all processes to reach barrier
+                wait::until(
+                    || coherence::load(self.counter, Ordering::Acquire) >= epoch_after,
+                    || wait::Stall {
+                        rank,
+                        word: "central barrier counter",
+                        condition: ">=",
+                        expected: epoch_after,
+                        observed: coherence::load(self.counter, Ordering::Acquire),
+                        missing: Some(self.arrivals.missing(total, epoch_after)),
+                    },
+                );
             });
         } else {
-            #[cfg(feature = "interrupts")]
-            for i in 0..total {
-                if i as i32 == exclude {
-                    continue;
-                }
-                unsafe {
-                    use std::os::fd::AsRawFd as _;
-                    assert_eq!(
-                        libc::pwrite(
-                            crate::PCI_FILE.as_raw_fd(),
-                            (i as u16).to_ne_bytes().as_ptr().cast(),
-                            std::mem::size_of::<u16>(),
-                            0,
-                        ),
-                        2,
-                    );
-                }
-            }
+            wait::wake_all(rank, total);
         }
 
         EPOCH.store(epoch_after, Ordering::Release);
     }
 }
+
+/// Epoch each rank last arrived at, for barriers whose shared counters do not
+/// say which ranks have arrived. Only read to name missing ranks on a stall.
+///
+/// Lives in the second half of the barrier area, after any counters.
+pub struct Arrivals<'pci>(&'pci [AtomicU64]);
+
+impl<'pci> Arrivals<'pci> {
+    const OFFSET: usize = Barrier::SIZE / 2;
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    /// Requires first `Barrier::SIZE` bytes to be zero-initialized.
+    pub unsafe fn new(address: *const u8) -> Self {
+        Self(slice::from_raw_parts(
+            address.add(Self::OFFSET).cast(),
+            (Barrier::SIZE - Self::OFFSET) / mem::size_of::<AtomicU64>(),
+        ))
+    }
+
+    pub fn arrive(&self, rank: ffi::c_int, epoch: u64) {
+        coherence::store(self.line(rank), epoch, Ordering::Release);
+    }
+
+    /// Ranks that have not arrived at `epoch` yet.
+    pub fn missing(&self, total: ffi::c_int, epoch: u64) -> Vec<ffi::c_int> {
+        (0..total)
+            .filter(|rank| coherence::load(self.line(*rank), Ordering::Acquire) < epoch)
+            .collect()
+    }
+
+    fn line(&self, rank: ffi::c_int) -> &'pci AtomicU64 {
+        &self.0[rank as usize * Self::WORDS]
+    }
+}
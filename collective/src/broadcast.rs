@@ -1,66 +1,65 @@
 use std::ffi;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering;
 
-use mpi::traits::Communicator as _;
+use crate::checksum;
+use crate::group::Group;
+use crate::guard::Layout;
+use crate::local::Local;
+use crate::segment::Segment;
 
-#[no_mangle]
-pub unsafe extern "C" fn MPI_Bcast(
-    buffer: *mut ffi::c_void,
-    count: ffi::c_int,
-    _: mpi::ffi::MPI_Datatype,
-    root: ffi::c_int,
-    comm: mpi::ffi::MPI_Comm,
-) -> ffi::c_int {
-    let local = std::slice::from_raw_parts_mut(buffer as *mut u8, count as usize);
-    let comm = crate::Communicator(comm);
-
-    if comm.size() == 1 {
-        return mpi::ffi::MPI_SUCCESS as ffi::c_int;
+/// Returns whether every checksum matched, which they do if disabled.
+pub(crate) unsafe fn broadcast(mut local: impl Local<u8>, root: ffi::c_int, group: &Group) -> bool {
+    if group.size == 1 {
+        return true;
     }
 
-    broadcast(local, root, comm);
-
-    mpi::ffi::MPI_SUCCESS as ffi::c_int
-}
-
-fn broadcast(local: &mut [u8], root: ffi::c_int, comm: crate::Communicator) {
-    static EPOCH: AtomicU64 = AtomicU64::new(0);
-
-    let epoch_before = EPOCH.load(Ordering::Acquire);
-    let epoch_after = epoch_before + comm.size() as u64;
+    let size = local.len();
 
-    if comm.rank() == root {
-        unsafe {
-            let mut shared = crate::PCI_MAP.lock().unwrap();
+    let mut pci_map = crate::pci().lock();
+    let (segment, slot) = Segment::split(&mut pci_map, group.rank, group.size);
 
-            shared[crate::CACHE_LINE_SIZE..][..local.len()].copy_from_slice(local);
+    let mut layout = Layout::new(slot);
+    let (shared, checksums) = carve(&mut layout, size);
+    let mut valid = true;
 
-            // https://doc.rust-lang.org/src/core/sync/atomic.rs.html#2090-2092
-            let epoch = &*shared.as_ptr().cast::<AtomicU64>();
+    if group.rank == root {
+        // Wait until everyone has read the previous broadcast from this slot
+        segment.reserve();
+        layout.initialize();
 
-            // Kick off broadcast
-            epoch.fetch_add(1, Ordering::AcqRel);
+        local.write(shared, checksums);
 
-            // Spin waiting for everyone to read
-            while epoch.load(Ordering::Acquire) < epoch_after {}
-        }
+        // Kick off broadcast
+        segment.publish();
     } else {
-        unsafe {
-            let shared = crate::PCI_MAP.lock().unwrap();
+        // Wait until broadcast starts
+        segment.wait(root);
 
-            // https://doc.rust-lang.org/src/core/sync/atomic.rs.html#2090-2092
-            let epoch = &*shared.as_ptr().cast::<AtomicU64>();
-
-            // Spin until broadcast starts
-            while epoch.load(Ordering::Acquire) == epoch_before {}
+        valid = local.read("broadcast", shared, checksums.as_deref());
+    }
 
-            local.copy_from_slice(&shared[crate::CACHE_LINE_SIZE..][..local.len()]);
+    layout.verify();
+    segment.complete();
+    valid
+}
 
-            // Update broadcaster
-            epoch.fetch_add(1, Ordering::AcqRel);
-        }
+/// Bytes of slot a broadcast of `size` bytes needs.
+pub(crate) fn required(size: usize, group: &Group) -> usize {
+    if group.size == 1 {
+        return 0;
     }
 
-    EPOCH.store(epoch_after, Ordering::Release);
+    let mut layout = Layout::measure();
+    carve(&mut layout, size);
+    layout.size()
+}
+
+/// Regions for `size` bytes of data, and their checksums.
+fn carve<'pci>(
+    layout: &mut Layout<'pci>,
+    size: usize,
+) -> (&'pci mut [u8], Option<&'pci mut [u32]>) {
+    let shared = layout.carve("data", size, 1);
+    let checksums = checksum::enabled()
+        .then(|| layout.carve_as::<u32>("checksums", checksum::chunks::<u8>(size)));
+    (shared, checksums)
 }
@@ -0,0 +1,281 @@
+//! C API over `Group`, for programs without an MPI runtime.
+//!
+//! Every function returns `COLLECTIVE_SUCCESS` or an error code, described by
+//! `collective_error_string`, and in more detail for the last failed call on
+//! the thread by `collective_last_error`. One group may be opened per process, with
+//! `collective_init`. The header and a pkg-config file are generated by the
+//! build, and installed with the library by `make install`.
+
+use std::cell::RefCell;
+use std::ffi;
+use std::mem;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::group::Config;
+use crate::group::Element;
+use crate::group::Group;
+
+pub const COLLECTIVE_SUCCESS: ffi::c_int = 0;
+/// Null, misaligned or partially overlapping buffers, unknown datatype, or
+/// rank out of range.
+pub const COLLECTIVE_ERROR_ARGUMENT: ffi::c_int = 1;
+pub const COLLECTIVE_ERROR_INITIALIZED: ffi::c_int = 2;
+pub const COLLECTIVE_ERROR_UNINITIALIZED: ffi::c_int = 3;
+/// The shared memory file could not be opened or mapped.
+pub const COLLECTIVE_ERROR_OPEN: ffi::c_int = 4;
+/// A bug, caught before unwinding into C.
+pub const COLLECTIVE_ERROR_INTERNAL: ffi::c_int = 5;
+/// Data was corrupted in shared memory, as found by `COLLECTIVE_CHECKSUM`.
+pub const COLLECTIVE_ERROR_CHECKSUM: ffi::c_int = 6;
+/// The buffer does not fit in a slot of the shared memory file.
+pub const COLLECTIVE_ERROR_SIZE: ffi::c_int = 7;
+
+pub const COLLECTIVE_FLOAT: ffi::c_int = 1;
+pub const COLLECTIVE_DOUBLE: ffi::c_int = 2;
+/// Also for raw bytes.
+pub const COLLECTIVE_INT8: ffi::c_int = 3;
+pub const COLLECTIVE_INT32: ffi::c_int = 4;
+
+static GROUP: Mutex<Option<Group>> = Mutex::new(None);
+
+thread_local! {
+    static LAST_ERROR: RefCell<ffi::CString> = RefCell::new(ffi::CString::default());
+}
+
+/// Join the group of `ranks` processes sharing the first `size` bytes of
+/// `path`, whose header must be zeroed before any rank joins.
+#[no_mangle]
+pub unsafe extern "C" fn collective_init(
+    path: *const ffi::c_char,
+    size: usize,
+    rank: ffi::c_int,
+    ranks: ffi::c_int,
+) -> ffi::c_int {
+    if path.is_null() || rank < 0 || rank >= ranks {
+        return COLLECTIVE_ERROR_ARGUMENT;
+    }
+    let path = match ffi::CStr::from_ptr(path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return COLLECTIVE_ERROR_ARGUMENT,
+    };
+
+    guard(|| {
+        let mut group = GROUP.lock().unwrap();
+        if group.is_some() {
+            return COLLECTIVE_ERROR_INITIALIZED;
+        }
+
+        let config = Config {
+            path,
+            size,
+            rank: rank as usize,
+            ranks: ranks as usize,
+        };
+        match Group::new(&config) {
+            Ok(opened) => {
+                *group = Some(opened);
+                COLLECTIVE_SUCCESS
+            }
+            Err(error) => fail(COLLECTIVE_ERROR_OPEN, error),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn collective_barrier() -> ffi::c_int {
+    with_group(|group| {
+        group.barrier();
+        COLLECTIVE_SUCCESS
+    })
+}
+
+/// Copy `count` elements of `buffer` from rank `root` into every other rank.
+#[no_mangle]
+pub unsafe extern "C" fn collective_bcast(
+    buffer: *mut ffi::c_void,
+    count: usize,
+    datatype: ffi::c_int,
+    root: ffi::c_int,
+) -> ffi::c_int {
+    match datatype {
+        COLLECTIVE_FLOAT => broadcast::<f32>(buffer, count, root),
+        COLLECTIVE_DOUBLE => broadcast::<f64>(buffer, count, root),
+        COLLECTIVE_INT8 => broadcast::<i8>(buffer, count, root),
+        COLLECTIVE_INT32 => broadcast::<i32>(buffer, count, root),
+        _ => COLLECTIVE_ERROR_ARGUMENT,
+    }
+}
+
+/// Element-wise sum of `count` elements of every rank's `send` into
+/// `receive`, which may be the same buffer, but may not otherwise overlap.
+#[no_mangle]
+pub unsafe extern "C" fn collective_allreduce(
+    send: *const ffi::c_void,
+    receive: *mut ffi::c_void,
+    count: usize,
+    datatype: ffi::c_int,
+) -> ffi::c_int {
+    match datatype {
+        COLLECTIVE_FLOAT => allreduce::<f32>(send, receive, count),
+        COLLECTIVE_DOUBLE => allreduce::<f64>(send, receive, count),
+        COLLECTIVE_INT8 => allreduce::<i8>(send, receive, count),
+        COLLECTIVE_INT32 => allreduce::<i32>(send, receive, count),
+        _ => COLLECTIVE_ERROR_ARGUMENT,
+    }
+}
+
+/// Leave the group. No further collectives may be called, and the group
+/// cannot be reopened.
+#[no_mangle]
+pub extern "C" fn collective_finalize() -> ffi::c_int {
+    guard(|| match GROUP.lock().unwrap().take() {
+        Some(group) => {
+            group.finalize();
+            COLLECTIVE_SUCCESS
+        }
+        None => COLLECTIVE_ERROR_UNINITIALIZED,
+    })
+}
+
+/// Static description of `error`.
+#[no_mangle]
+pub extern "C" fn collective_error_string(error: ffi::c_int) -> *const ffi::c_char {
+    let message: &'static [u8] = match error {
+        COLLECTIVE_SUCCESS => b"Success\0",
+        COLLECTIVE_ERROR_ARGUMENT => b"Invalid argument\0",
+        COLLECTIVE_ERROR_INITIALIZED => b"Group already initialized\0",
+        COLLECTIVE_ERROR_UNINITIALIZED => b"Group not initialized\0",
+        COLLECTIVE_ERROR_OPEN => b"Failed to open shared memory\0",
+        COLLECTIVE_ERROR_INTERNAL => b"Internal error\0",
+        COLLECTIVE_ERROR_CHECKSUM => b"Checksum mismatch\0",
+        COLLECTIVE_ERROR_SIZE => b"Buffer too large for shared memory\0",
+        _ => b"Unknown error\0",
+    };
+    message.as_ptr().cast()
+}
+
+/// Cause of the last error returned on this thread, other than for invalid
+/// arguments, or an empty string. Valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn collective_last_error() -> *const ffi::c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+unsafe fn broadcast<T: Element>(
+    buffer: *mut ffi::c_void,
+    count: usize,
+    root: ffi::c_int,
+) -> ffi::c_int {
+    let Some(buffer) = slice_mut::<T>(buffer, count) else {
+        return COLLECTIVE_ERROR_ARGUMENT;
+    };
+
+    with_group(|group| match usize::try_from(root) {
+        Ok(root) if root < group.size() => match group.fits_broadcast::<T>(count) {
+            Ok(()) => checked(group.broadcast(buffer, root)),
+            Err(error) => fail(COLLECTIVE_ERROR_SIZE, error),
+        },
+        _ => COLLECTIVE_ERROR_ARGUMENT,
+    })
+}
+
+unsafe fn allreduce<T: Element>(
+    send: *const ffi::c_void,
+    receive: *mut ffi::c_void,
+    count: usize,
+) -> ffi::c_int {
+    // Slices may not alias, so in place reductions send a copy, and other
+    // overlapping buffers are rejected
+    let in_place = send == receive.cast_const();
+    let size = count.saturating_mul(mem::size_of::<T>());
+    let (send_start, receive_start) = (send as usize, receive as usize);
+    if !in_place
+        && send_start < receive_start.saturating_add(size)
+        && receive_start < send_start.saturating_add(size)
+    {
+        return COLLECTIVE_ERROR_ARGUMENT;
+    }
+
+    let Some(receive) = slice_mut::<T>(receive, count) else {
+        return COLLECTIVE_ERROR_ARGUMENT;
+    };
+
+    let copy;
+    let send = match in_place {
+        true => {
+            copy = receive.to_vec();
+            &copy[..]
+        }
+        false => match slice::<T>(send, count) {
+            Some(send) => send,
+            None => return COLLECTIVE_ERROR_ARGUMENT,
+        },
+    };
+
+    with_group(|group| match group.fits_allreduce::<T>(count) {
+        Ok(()) => checked(group.allreduce(send, receive)),
+        Err(error) => fail(COLLECTIVE_ERROR_SIZE, error),
+    })
+}
+
+/// `count` elements at `buffer`, or `None` if null, misaligned, or larger
+/// than any allocation.
+unsafe fn slice<'a, T>(buffer: *const ffi::c_void, count: usize) -> Option<&'a [T]> {
+    match count {
+        0 => Some(&[]),
+        _ if !valid::<T>(buffer, count) => None,
+        _ => Some(std::slice::from_raw_parts(buffer.cast(), count)),
+    }
+}
+
+unsafe fn slice_mut<'a, T>(buffer: *mut ffi::c_void, count: usize) -> Option<&'a mut [T]> {
+    match count {
+        0 => Some(&mut []),
+        _ if !valid::<T>(buffer, count) => None,
+        _ => Some(std::slice::from_raw_parts_mut(buffer.cast(), count)),
+    }
+}
+
+fn valid<T>(buffer: *const ffi::c_void, count: usize) -> bool {
+    !buffer.is_null()
+        && buffer.cast::<T>().is_aligned()
+        && count
+            .checked_mul(mem::size_of::<T>())
+            .is_some_and(|size| size <= isize::MAX as usize)
+}
+
+fn with_group<F: FnOnce(&Group) -> ffi::c_int>(f: F) -> ffi::c_int {
+    guard(|| match GROUP.lock().unwrap().as_ref() {
+        Some(group) => f(group),
+        None => COLLECTIVE_ERROR_UNINITIALIZED,
+    })
+}
+
+fn checked(result: anyhow::Result<()>) -> ffi::c_int {
+    match result {
+        Ok(()) => COLLECTIVE_SUCCESS,
+        Err(error) => fail(COLLECTIVE_ERROR_CHECKSUM, error),
+    }
+}
+
+/// Record `error` for `collective_last_error`, and return `code`.
+fn fail(code: ffi::c_int, error: impl std::fmt::Display) -> ffi::c_int {
+    // Interior nul bytes would truncate the message anyway
+    let message = format!("{:#}", error).replace('\0', "");
+    LAST_ERROR.with(|last| *last.borrow_mut() = ffi::CString::new(message).unwrap());
+    code
+}
+
+/// Panics must not unwind into C.
+fn guard<F: FnOnce() -> ffi::c_int>(f: F) -> ffi::c_int {
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => message,
+            (_, Some(message)) => message.as_str(),
+            (None, None) => "unknown panic",
+        };
+        fail(COLLECTIVE_ERROR_INTERNAL, message)
+    })
+}
@@ -0,0 +1,182 @@
+//! Per-chunk CRC32C checksums of data passing through the segment, enabled by
+//! setting `COLLECTIVE_CHECKSUM`.
+//!
+//! Writers checksum each `CHUNK_SIZE` chunk of what they meant to write, and
+//! readers recompute it over what they read back. Mismatches are counted in
+//! metrics, and abort the job if `COLLECTIVE_CHECKSUM_ABORT` is set, or else
+//! fail the call with `MPI_ERR_OTHER`.
+
+use std::env;
+use std::mem;
+
+use once_cell::sync::Lazy;
+
+use crate::coherence;
+use crate::metrics;
+
+pub const CHUNK_SIZE: usize = crate::PAGE_SIZE;
+
+static ENABLED: Lazy<bool> = Lazy::new(|| env::var("COLLECTIVE_CHECKSUM").is_ok());
+static ABORT: Lazy<bool> = Lazy::new(|| env::var("COLLECTIVE_CHECKSUM_ABORT").is_ok());
+
+#[cfg(target_arch = "x86_64")]
+static SSE42: Lazy<bool> = Lazy::new(|| is_x86_feature_detected!("sse4.2"));
+
+pub fn enabled() -> bool {
+    *ENABLED
+}
+
+/// Number of checksums covering `count` elements of `T`.
+pub fn chunks<T>(count: usize) -> usize {
+    (count * mem::size_of::<T>()).div_ceil(CHUNK_SIZE)
+}
+
+/// Checksum each chunk of `data` into `checksums`, and write them back to
+/// shared memory.
+pub fn compute<T>(data: &[T], checksums: &mut [u32]) {
+    metrics::time!(metrics::timers::CHECKSUM, {
+        bytes(data)
+            .chunks(CHUNK_SIZE)
+            .zip(checksums.iter_mut())
+            .for_each(|(chunk, checksum)| *checksum = crc32c(chunk));
+        coherence::flush(checksums);
+    });
+}
+
+/// Check each chunk of `data` against `checksums`, returning whether all
+/// matched. `data` may start partway through the checksummed buffer, at
+/// chunk `first`.
+#[must_use]
+pub fn verify<T>(name: &str, data: &[T], checksums: &[u32], first: usize) -> bool {
+    metrics::time!(metrics::timers::CHECKSUM, {
+        coherence::invalidate(checksums);
+
+        let mut valid = true;
+        for (index, chunk) in bytes(data).chunks(CHUNK_SIZE).enumerate() {
+            let expected = checksums[first + index];
+            let actual = crc32c(chunk);
+
+            metrics::increment!(metrics::counters::CHECKSUM_VERIFIED);
+            if actual == expected {
+                continue;
+            }
+
+            metrics::increment!(metrics::counters::CHECKSUM_MISMATCHED);
+            valid = false;
+
+            if *ABORT {
+                crate::abort(format!(
+                    "checksum mismatch in {} chunk {} (offset {:#x}): expected {:#010x}, computed {:#010x}",
+                    name,
+                    first + index,
+                    (first + index) * CHUNK_SIZE,
+                    expected,
+                    actual,
+                ));
+            }
+        }
+        valid
+    })
+}
+
+fn bytes<T>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr().cast(), mem::size_of_val(data)) }
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if *SSE42 {
+        return unsafe { crc32c_sse42(data) };
+    }
+
+    crc32c_scalar(data)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(data: &[u8]) -> u32 {
+    use std::arch::x86_64::*;
+
+    let mut crc = !0u64;
+    let (words, tail) = data.split_at(data.len() & !7);
+    for word in words.chunks_exact(8) {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+
+    let mut crc = crc as u32;
+    for byte in tail {
+        crc = _mm_crc32_u8(crc, *byte);
+    }
+    !crc
+}
+
+fn crc32c_scalar(data: &[u8]) -> u32 {
+    // Reflected Castagnoli polynomial
+    const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+    static TABLE: Lazy<[u32; 256]> = Lazy::new(|| {
+        let mut table = [0; 256];
+        for (byte, entry) in table.iter_mut().enumerate() {
+            *entry = (0..8).fold(byte as u32, |crc, _| match crc & 1 {
+                1 => (crc >> 1) ^ POLYNOMIAL,
+                _ => crc >> 1,
+            });
+        }
+        table
+    });
+
+    !data.iter().fold(!0, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        assert_eq!(crc32c_scalar(b""), 0);
+        assert_eq!(crc32c_scalar(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8A91_36AA);
+    }
+
+    /// SSE4.2, where available, agrees with the scalar fallback on every
+    /// split into 8-byte words and a tail, from unaligned starts.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sse42_matches_scalar() {
+        if !*SSE42 {
+            return;
+        }
+
+        let data = (0..100u32)
+            .map(|index| (index.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        for start in 0..8 {
+            for len in 0..data.len() - start {
+                let data = &data[start..][..len];
+                assert_eq!(
+                    unsafe { crc32c_sse42(data) },
+                    crc32c_scalar(data),
+                    "{} bytes at {}",
+                    len,
+                    start
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut data = vec![1.0_f64; CHUNK_SIZE / 8 * 2 + 3];
+        let mut checksums = vec![0; chunks::<f64>(data.len())];
+        compute(&data, &mut checksums);
+        assert!(verify("data", &data, &checksums, 0));
+        assert!(verify("tail", &data[CHUNK_SIZE / 8..], &checksums, 1));
+
+        data[CHUNK_SIZE / 8 + 1] = 2.0;
+        assert!(!verify("data", &data, &checksums, 0));
+    }
+}
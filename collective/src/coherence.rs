@@ -0,0 +1,154 @@
+//! Explicit cache maintenance for shared memory without cross-host coherence.
+//!
+//! Enabled by setting `COLLECTIVE_NONCOHERENT`. Writes to the segment are then
+//! written back with `clwb` (or `clflushopt`, or `clflush`) followed by
+//! `sfence`, and reads of peer data are preceded by `clflushopt` (or
+//! `clflush`) to invalidate any stale cached copy. Otherwise every function
+//! here is a no-op, apart from the atomic access itself.
+
+use std::env;
+use std::mem;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use once_cell::sync::Lazy;
+
+#[derive(Copy, Clone)]
+enum Instruction {
+    Clwb,
+    Clflushopt,
+    Clflush,
+}
+
+struct Mode {
+    writeback: Instruction,
+    invalidate: Instruction,
+}
+
+static MODE: Lazy<Option<Mode>> = Lazy::new(|| match env::var("COLLECTIVE_NONCOHERENT") {
+    Err(_) => None,
+    Ok(_) => Some(Mode::detect()),
+});
+
+impl Mode {
+    #[cfg(target_arch = "x86_64")]
+    fn detect() -> Self {
+        // CPUID.(EAX=07H, ECX=0H):EBX
+        #[allow(unused_unsafe)]
+        let features = unsafe { std::arch::x86_64::__cpuid_count(7, 0).ebx };
+        let clflushopt = features & (1 << 23) != 0;
+        let clwb = features & (1 << 24) != 0;
+
+        let invalidate = match clflushopt {
+            true => Instruction::Clflushopt,
+            false => Instruction::Clflush,
+        };
+
+        let writeback = match clwb {
+            true => Instruction::Clwb,
+            false => invalidate,
+        };
+
+        Mode {
+            writeback,
+            invalidate,
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn detect() -> Self {
+        panic!("COLLECTIVE_NONCOHERENT is only supported on x86_64");
+    }
+}
+
+/// Write back `data` to shared memory, so it is visible to peers.
+pub fn flush<T>(data: &[T]) {
+    if let Some(mode) = &*MODE {
+        unsafe {
+            each_line(data, mode.writeback);
+
+            // Order write-backs before subsequent stores, e.g. releasing a flag
+            #[cfg(target_arch = "x86_64")]
+            std::arch::x86_64::_mm_sfence();
+        }
+    }
+}
+
+/// Discard any cached copy of `data`, so the next read observes peer writes.
+pub fn invalidate<T>(data: &[T]) {
+    if let Some(mode) = &*MODE {
+        unsafe {
+            each_line(data, mode.invalidate);
+
+            // Order invalidations before subsequent loads
+            #[cfg(target_arch = "x86_64")]
+            std::arch::x86_64::_mm_mfence();
+        }
+    }
+}
+
+pub fn load(atomic: &AtomicU64, ordering: Ordering) -> u64 {
+    invalidate(std::slice::from_ref(atomic));
+    atomic.load(ordering)
+}
+
+pub fn store(atomic: &AtomicU64, value: u64, ordering: Ordering) {
+    atomic.store(value, ordering);
+    flush(std::slice::from_ref(atomic));
+}
+
+pub fn fetch_add(atomic: &AtomicU64, value: u64, ordering: Ordering) -> u64 {
+    invalidate(std::slice::from_ref(atomic));
+    let previous = atomic.fetch_add(value, ordering);
+    flush(std::slice::from_ref(atomic));
+    previous
+}
+
+pub fn swap(atomic: &AtomicU64, value: u64, ordering: Ordering) -> u64 {
+    invalidate(std::slice::from_ref(atomic));
+    let previous = atomic.swap(value, ordering);
+    flush(std::slice::from_ref(atomic));
+    previous
+}
+
+pub fn compare_exchange(
+    atomic: &AtomicU64,
+    current: u64,
+    new: u64,
+    success: Ordering,
+    failure: Ordering,
+) -> Result<u64, u64> {
+    invalidate(std::slice::from_ref(atomic));
+    let result = atomic.compare_exchange(current, new, success, failure);
+    if result.is_ok() {
+        flush(std::slice::from_ref(atomic));
+    }
+    result
+}
+
+unsafe fn each_line<T>(data: &[T], instruction: Instruction) {
+    let start = data.as_ptr() as usize & !(crate::CACHE_LINE_SIZE - 1);
+    let end = data.as_ptr() as usize + mem::size_of_val(data);
+
+    for line in (start..end).step_by(crate::CACHE_LINE_SIZE) {
+        execute(line as *const u8, instruction);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn execute(line: *const u8, instruction: Instruction) {
+    use std::arch::asm;
+
+    match instruction {
+        Instruction::Clwb => asm!("clwb [{}]", in(reg) line, options(nostack, preserves_flags)),
+        Instruction::Clflushopt => {
+            asm!("clflushopt [{}]", in(reg) line, options(nostack, preserves_flags))
+        }
+        Instruction::Clflush => {
+            asm!("clflush [{}]", in(reg) line, options(nostack, preserves_flags))
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn execute(_: *const u8, _: Instruction) {}
@@ -0,0 +1,142 @@
+//! Copy engine for transfers into and out of the shared segment.
+//!
+//! Transfers of at least `COLLECTIVE_STREAMING_THRESHOLD` bytes (default
+//! 256KiB) bypass the cache: writes into the segment use non-temporal stores
+//! in full cache lines, which also issue full bursts to write-combining
+//! mappings, and reads out of the segment use non-temporal loads. Smaller
+//! transfers fall back to `copy_from_slice`.
+
+use std::env;
+use std::mem;
+use std::slice;
+
+use once_cell::sync::Lazy;
+
+use crate::coherence;
+
+static THRESHOLD: Lazy<usize> = Lazy::new(|| match env::var("COLLECTIVE_STREAMING_THRESHOLD") {
+    Err(_) => 256 * 1024,
+    Ok(threshold) => threshold
+        .parse::<usize>()
+        .expect("Failed to parse COLLECTIVE_STREAMING_THRESHOLD as usize"),
+});
+
+#[cfg(target_arch = "x86_64")]
+static STREAMING_LOAD: Lazy<bool> = Lazy::new(|| is_x86_feature_detected!("sse4.1"));
+
+/// Distance in bytes to prefetch ahead of the source.
+#[cfg(target_arch = "x86_64")]
+const PREFETCH_DISTANCE: usize = 8 * crate::CACHE_LINE_SIZE;
+
+/// Copy `local` into `shared` memory.
+pub fn write<T: Copy>(shared: &mut [T], local: &[T]) {
+    if mem::size_of_val(local) < *THRESHOLD {
+        shared.copy_from_slice(local);
+    } else {
+        assert_eq!(shared.len(), local.len());
+
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            stream_store(bytes_mut(shared), bytes(local));
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        shared.copy_from_slice(local);
+    }
+
+    coherence::flush(shared);
+}
+
+/// Copy `shared` memory into `local`.
+pub fn read<T: Copy>(local: &mut [T], shared: &[T]) {
+    coherence::invalidate(shared);
+
+    if mem::size_of_val(shared) < *THRESHOLD {
+        return local.copy_from_slice(shared);
+    }
+
+    assert_eq!(shared.len(), local.len());
+
+    #[cfg(target_arch = "x86_64")]
+    if *STREAMING_LOAD {
+        unsafe {
+            stream_load(bytes_mut(local), bytes(shared));
+        }
+        return;
+    }
+
+    local.copy_from_slice(shared);
+}
+
+fn bytes<T: Copy>(slice: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(slice.as_ptr().cast(), mem::size_of_val(slice)) }
+}
+
+fn bytes_mut<T: Copy>(slice: &mut [T]) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(slice.as_mut_ptr().cast(), mem::size_of_val(slice)) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn stream_store(destination: &mut [u8], source: &[u8]) {
+    use std::arch::x86_64::*;
+
+    // Align destination to a cache line so stores fill whole lines
+    let head = destination
+        .as_ptr()
+        .align_offset(crate::CACHE_LINE_SIZE)
+        .min(destination.len());
+    let body = (destination.len() - head) / crate::CACHE_LINE_SIZE * crate::CACHE_LINE_SIZE;
+
+    destination[..head].copy_from_slice(&source[..head]);
+
+    for offset in (head..head + body).step_by(crate::CACHE_LINE_SIZE) {
+        let from = source.as_ptr().add(offset);
+        let to = destination.as_mut_ptr().add(offset);
+
+        _mm_prefetch::<_MM_HINT_NTA>(from.wrapping_add(PREFETCH_DISTANCE).cast());
+
+        for lane in 0..4 {
+            let value = _mm_loadu_si128(from.add(lane * 16).cast());
+            _mm_stream_si128(to.add(lane * 16).cast(), value);
+        }
+    }
+
+    destination[head + body..].copy_from_slice(&source[head + body..]);
+
+    // Non-temporal stores are weakly ordered
+    _mm_sfence();
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn stream_load(destination: &mut [u8], source: &[u8]) {
+    use std::arch::x86_64::*;
+
+    // Non-temporal loads require an aligned source
+    let head = source
+        .as_ptr()
+        .align_offset(crate::CACHE_LINE_SIZE)
+        .min(source.len());
+    let body = (source.len() - head) / crate::CACHE_LINE_SIZE * crate::CACHE_LINE_SIZE;
+
+    // Streaming loads from write-combining memory are weakly ordered, so
+    // fence them behind the acquire that synchronized with the writer
+    _mm_mfence();
+
+    destination[..head].copy_from_slice(&source[..head]);
+
+    for offset in (head..head + body).step_by(crate::CACHE_LINE_SIZE) {
+        let from = source.as_ptr().add(offset);
+        let to = destination.as_mut_ptr().add(offset);
+
+        _mm_prefetch::<_MM_HINT_NTA>(from.wrapping_add(PREFETCH_DISTANCE).cast());
+
+        for lane in 0..4 {
+            let value = _mm_stream_load_si128(from.add(lane * 16).cast());
+            _mm_storeu_si128(to.add(lane * 16).cast(), value);
+        }
+    }
+
+    destination[head + body..].copy_from_slice(&source[head + body..]);
+}
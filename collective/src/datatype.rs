@@ -1,35 +1,55 @@
-pub(crate) trait MpiType {
-    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool;
+use crate::kernel;
 
-    fn sum_mut(&mut self, other: &Self);
+pub trait MpiType: Sized + Copy + Default {
+    /// Whether summation rounds, so `sum_slice_compensated` is worthwhile.
+    const INEXACT: bool = false;
+
+    /// Whether this is `f32`, the only type whose sums can be quantized.
+    const QUANTIZABLE: bool = false;
+
+    /// Element-wise `shared += other`, wrapping on integer overflow.
+    fn sum_slice_mut(shared: &mut [Self], other: &[Self]);
+
+    /// Like `sum_slice_mut`, but accumulates rounding error into
+    /// `compensation`, which the caller adds to the final result.
+    fn sum_slice_compensated(shared: &mut [Self], _compensation: &mut [Self], other: &[Self]) {
+        Self::sum_slice_mut(shared, other)
+    }
 }
 
 impl MpiType for f32 {
-    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
-        unsafe { datatype == mpi::ffi::RSMPI_FLOAT }
+    const INEXACT: bool = true;
+    const QUANTIZABLE: bool = true;
+
+    fn sum_slice_mut(shared: &mut [Self], other: &[Self]) {
+        kernel::f32::sum(shared, other);
     }
 
-    fn sum_mut(&mut self, other: &Self) {
-        *self += other;
+    fn sum_slice_compensated(shared: &mut [Self], compensation: &mut [Self], other: &[Self]) {
+        kernel::compensated::f32::sum(shared, compensation, other);
     }
 }
 
-impl MpiType for i8 {
-    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
-        unsafe { datatype == mpi::ffi::RSMPI_INT8_T }
+impl MpiType for f64 {
+    const INEXACT: bool = true;
+
+    fn sum_slice_mut(shared: &mut [Self], other: &[Self]) {
+        kernel::f64::sum(shared, other);
     }
 
-    fn sum_mut(&mut self, other: &Self) {
-        *self += other;
+    fn sum_slice_compensated(shared: &mut [Self], compensation: &mut [Self], other: &[Self]) {
+        kernel::compensated::f64::sum(shared, compensation, other);
     }
 }
 
-impl MpiType for i32 {
-    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
-        unsafe { datatype == mpi::ffi::RSMPI_INT32_T }
+impl MpiType for i8 {
+    fn sum_slice_mut(shared: &mut [Self], other: &[Self]) {
+        kernel::i8::sum(shared, other);
     }
+}
 
-    fn sum_mut(&mut self, other: &Self) {
-        *self += other;
+impl MpiType for i32 {
+    fn sum_slice_mut(shared: &mut [Self], other: &[Self]) {
+        kernel::i32::sum(shared, other);
     }
 }
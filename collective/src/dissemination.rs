@@ -0,0 +1,84 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::barrier::Barrier;
+use crate::coherence;
+use crate::metrics;
+use crate::wait;
+
+/// Dissemination barrier using only single-writer flags.
+///
+/// In round `k`, rank `i` signals rank `(i + 2^k) % n` and waits for rank
+/// `(i - 2^k) % n`, so after `ceil(log2(n))` rounds every rank has transitively
+/// heard from every other. Each flag is written by exactly one rank and holds
+/// the epoch it last signaled, so no atomic read-modify-write is needed.
+///
+/// | Rank 0 Round 0      |
+/// | Rank 0 Round 1      |
+/// | ...                 |
+/// | Rank 1 Round 0      |
+/// | ...                 |
+pub struct Dissemination<'pci>(&'pci [AtomicU64]);
+
+impl<'pci> Dissemination<'pci> {
+    const ROUNDS: usize = 8;
+
+    /// Requires first `Barrier::SIZE` bytes to be zero-initialized.
+    pub unsafe fn new(address: *const u8) -> Self {
+        Self(slice::from_raw_parts(
+            address.cast(),
+            Barrier::SIZE / mem::size_of::<AtomicU64>(),
+        ))
+    }
+
+    pub fn wait(&self, rank: ffi::c_int, total: ffi::c_int) {
+        static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+        let epoch = EPOCH.load(Ordering::Acquire) + 1;
+        let rank = rank as usize;
+        let total = total as usize;
+
+        let mut distance = 1;
+        let mut round = 0;
+
+        metrics::time!(metrics::timers::BARRIER, {
+            while distance < total {
+                let partner = (rank + distance) % total;
+
+                coherence::store(self.flag(partner, round), epoch, Ordering::Release);
+                wait::wake(partner as ffi::c_int);
+
+                // Wait for signal from (rank - distance) % total
+                wait::until(
+                    || coherence::load(self.flag(rank, round), Ordering::Acquire) >= epoch,
+                    || wait::Stall {
+                        rank: rank as ffi::c_int,
+                        word: "dissemination barrier flag",
+                        condition: ">=",
+                        expected: epoch,
+                        observed: coherence::load(self.flag(rank, round), Ordering::Acquire),
+                        missing: Some(vec![((rank + total - distance) % total) as ffi::c_int]),
+                    },
+                );
+
+                distance *= 2;
+                round += 1;
+            }
+        });
+
+        EPOCH.store(epoch, Ordering::Release);
+    }
+
+    fn flag(&self, rank: usize, round: usize) -> &'pci AtomicU64 {
+        let offset = (rank * Self::ROUNDS + round) * crate::CACHE_LINE_SIZE;
+        assert!(
+            round < Self::ROUNDS && offset < Barrier::SIZE,
+            "Too many ranks for dissemination barrier",
+        );
+
+        &self.0[offset / mem::size_of::<AtomicU64>()]
+    }
+}
@@ -0,0 +1,237 @@
+//! Safe collectives over a shared memory file, without MPI.
+//!
+//! Each process opens the same file with its own rank, and then calls the same
+//! collectives in the same order as every other rank. The MPI interposer runs
+//! the same algorithms, with ranks taken from the communicator instead.
+
+use std::env;
+use std::ffi;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use once_cell::sync::Lazy;
+
+use crate::allreduce;
+use crate::broadcast;
+use crate::datatype::MpiType;
+use crate::kernel;
+use crate::liveness;
+use crate::metrics;
+use crate::segment;
+use crate::segment::Segment;
+use crate::signature;
+use crate::signature::Collective;
+use crate::signature::Signature;
+use crate::wait;
+
+/// Element types supported by `Group::broadcast` and `Group::allreduce`.
+pub trait Element: MpiType {}
+
+impl Element for f32 {}
+impl Element for f64 {}
+impl Element for i8 {}
+impl Element for i32 {}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Shared memory file, e.g. an ivshmem BAR or a file on a DAX device.
+    pub path: PathBuf,
+    /// Bytes of `path` to map.
+    pub size: usize,
+    pub rank: usize,
+    /// Number of ranks sharing `path`.
+    pub ranks: usize,
+}
+
+impl Config {
+    /// Read from `COLLECTIVE_PCI_PATH`, `COLLECTIVE_PCI_SIZE`,
+    /// `COLLECTIVE_RANK` and `COLLECTIVE_RANKS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let parse = |name: &str| -> anyhow::Result<usize> {
+            env::var(name)
+                .with_context(|| anyhow!("Missing {} environment variable", name))?
+                .parse::<usize>()
+                .with_context(|| anyhow!("Failed to parse {} as usize", name))
+        };
+
+        Ok(Config {
+            path: crate::initialize_path()?,
+            size: crate::initialize_size()?,
+            rank: parse("COLLECTIVE_RANK")?,
+            ranks: parse("COLLECTIVE_RANKS")?,
+        })
+    }
+}
+
+/// Ranks sharing one shared memory file.
+///
+/// Every collective blocks until all ranks have called it, and must be called
+/// by every rank in the same order, with the same element type and count.
+#[derive(Debug)]
+pub struct Group {
+    pub(crate) rank: ffi::c_int,
+    pub(crate) size: ffi::c_int,
+}
+
+impl Group {
+    /// Open and map `config.path`, whose first `segment::HEADER_SIZE` bytes
+    /// must be zeroed before any rank joins, as a new file is, and as
+    /// `finalize` leaves them. Only one group may be opened per
+    /// process, and not alongside the MPI interposer.
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        if config.rank >= config.ranks {
+            return Err(anyhow!(
+                "Rank {} out of range for {} ranks",
+                config.rank,
+                config.ranks,
+            ));
+        }
+        if config.ranks > segment::MAX_RANKS {
+            return Err(anyhow!(
+                "{} ranks exceed the {} the segment header has room for",
+                config.ranks,
+                segment::MAX_RANKS,
+            ));
+        }
+        if config.size < segment::MIN_SIZE {
+            return Err(anyhow!(
+                "{} bytes too small for the segment header and two slots, which need {}",
+                config.size,
+                segment::MIN_SIZE,
+            ));
+        }
+
+        let pci = crate::Pci::open(&config.path, config.size)?;
+        if crate::PCI.set(pci).is_err() {
+            return Err(anyhow!("Shared memory already opened"));
+        }
+
+        let group = Group {
+            rank: ffi::c_int::try_from(config.rank)?,
+            size: ffi::c_int::try_from(config.ranks)?,
+        };
+
+        Lazy::force(&kernel::LEVEL);
+        wait::initialize();
+        liveness::initialize(group.rank, group.size);
+
+        Ok(group)
+    }
+
+    pub fn rank(&self) -> usize {
+        self.rank as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Block until every rank has called `barrier`.
+    pub fn barrier(&self) {
+        wait::enter("Group::barrier");
+        signature::enter(Signature::sized(Collective::Barrier, 0, 0, None));
+
+        let mut pci_map = crate::pci().lock();
+        let (segment, _) = Segment::split(&mut pci_map, self.rank, self.size);
+        segment.barrier().wait(self.rank, self.size);
+        segment.complete();
+    }
+
+    /// Copy `data` from rank `root` into every other rank's `data`. Fails
+    /// without taking a slot if `data` does not fit in one, and otherwise if
+    /// checksums are enabled and found `data` corrupted in shared memory.
+    pub fn broadcast<T: Element>(&self, data: &mut [T], root: usize) -> anyhow::Result<()> {
+        assert!(root < self.size(), "Root {} out of range", root);
+        self.fits_broadcast::<T>(data.len())?;
+
+        wait::enter("Group::broadcast");
+        signature::enter(Signature::sized(
+            Collective::Bcast,
+            data.len(),
+            std::mem::size_of::<T>(),
+            Some(root as ffi::c_int),
+        ));
+
+        let valid = unsafe { broadcast::broadcast(bytes_mut(data), root as ffi::c_int, self) };
+        checked("broadcast", valid)
+    }
+
+    /// Element-wise sum of every rank's `send` into `receive`. Fails like
+    /// `broadcast`.
+    pub fn allreduce<T: Element>(&self, send: &[T], receive: &mut [T]) -> anyhow::Result<()> {
+        assert_eq!(send.len(), receive.len(), "Mismatched buffer lengths");
+        self.fits_allreduce::<T>(send.len())?;
+
+        metrics::reset();
+        wait::enter("Group::allreduce");
+        signature::enter(Signature::sized(
+            Collective::Allreduce,
+            send.len(),
+            std::mem::size_of::<T>(),
+            None,
+        ));
+        let valid = metrics::time!(metrics::timers::TOTAL, {
+            unsafe { allreduce::reduce(send, receive, allreduce::Sum, self) }
+        });
+        metrics::dump();
+        checked("allreduce", valid)
+    }
+
+    /// Fail if `broadcast` of `len` elements needs more than a slot.
+    pub(crate) fn fits_broadcast<T: Element>(&self, len: usize) -> anyhow::Result<()> {
+        let required = broadcast::required(len * std::mem::size_of::<T>(), self);
+        fits("broadcast", required)
+    }
+
+    /// Fail if `allreduce` of `len` elements needs more than a slot.
+    pub(crate) fn fits_allreduce<T: Element>(&self, len: usize) -> anyhow::Result<()> {
+        let required = allreduce::required::<T, _>(len, &allreduce::Sum, self);
+        fits("allreduce", required)
+    }
+
+    /// Leave the group once every rank has completed the same collectives,
+    /// aborting if any completed more, and zero the segment header for the
+    /// next group, as `MPI_Finalize` does.
+    pub fn finalize(self) {
+        metrics::flush();
+
+        // Every rank has started its last collective
+        self.barrier();
+
+        let mut pci_map = crate::pci().lock();
+        let ahead = Segment::leave(&mut pci_map, self.rank, self.size);
+        if !ahead.is_empty() {
+            crate::abort(format!(
+                "rank {} found ranks {:?} completed more collectives by Group::finalize",
+                self.rank, ahead,
+            ));
+        }
+    }
+}
+
+/// Fail before taking a slot if `collective` needs more of it than there is.
+fn fits(collective: &str, required: usize) -> anyhow::Result<()> {
+    let capacity = crate::pci().slot_size();
+    match required <= capacity {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "{} needs {} bytes of slot, but slots have {}",
+            collective,
+            required,
+            capacity,
+        )),
+    }
+}
+
+fn checked(collective: &str, valid: bool) -> anyhow::Result<()> {
+    match valid {
+        true => Ok(()),
+        false => Err(anyhow!("Checksum mismatch in {}", collective)),
+    }
+}
+
+fn bytes_mut<T: Element>(data: &mut [T]) -> &mut [u8] {
+    // Elements are plain numbers, without padding
+    unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr().cast(), std::mem::size_of_val(data)) }
+}
@@ -0,0 +1,298 @@
+//! Carving of named regions out of a slot.
+//!
+//! With `feature = "guards"`, every region is followed by a page-sized guard
+//! zone filled with a canary, and alignment padding and up to a page of the
+//! slot after the last region are poisoned. The rank that
+//! initializes the slot writes both patterns, and every rank verifies them at
+//! the end of each collective, aborting with the names and slot offsets of any
+//! overwritten zones.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+
+use crate::coherence;
+
+#[cfg(feature = "guards")]
+const GUARD_SIZE: usize = crate::PAGE_SIZE;
+#[cfg(not(feature = "guards"))]
+const GUARD_SIZE: usize = 0;
+
+const CANARY: u8 = 0xCA;
+const POISON: u8 = 0xDB;
+
+struct Zone {
+    name: String,
+    offset: usize,
+    size: usize,
+    pattern: u8,
+}
+
+pub struct Layout<'pci> {
+    base: *mut u8,
+    offset: usize,
+    capacity: usize,
+    zones: Vec<Zone>,
+    slot: PhantomData<&'pci mut [u8]>,
+}
+
+impl<'pci> Layout<'pci> {
+    pub fn new(slot: &'pci mut [u8]) -> Self {
+        // Regions are aligned by slot offset
+        assert_eq!(
+            slot.as_ptr().align_offset(crate::PAGE_SIZE),
+            0,
+            "Slot not page aligned"
+        );
+        Layout {
+            base: slot.as_mut_ptr(),
+            offset: 0,
+            capacity: slot.len(),
+            zones: Vec::new(),
+            slot: PhantomData,
+        }
+    }
+
+    /// Layout of an unbounded slot, whose regions are all empty, for `size` to
+    /// measure the slot a collective needs before taking a real one.
+    pub fn measure() -> Self {
+        Layout {
+            base: ptr::null_mut(),
+            offset: 0,
+            capacity: usize::MAX,
+            zones: Vec::new(),
+            slot: PhantomData,
+        }
+    }
+
+    /// Bytes carved so far, including guard zones.
+    pub fn size(&self) -> usize {
+        self.offset
+    }
+
+    /// Split off `size` bytes aligned to `align`, for region `name`.
+    pub fn carve(&mut self, name: impl fmt::Display, size: usize, align: usize) -> &'pci mut [u8] {
+        let padding = self
+            .offset
+            .checked_next_multiple_of(align)
+            .unwrap_or(usize::MAX)
+            - self.offset;
+        self.zone("padding before", &name, padding, POISON);
+
+        let region = self.take(size);
+        self.zone("guard after", &name, GUARD_SIZE, CANARY);
+
+        region
+    }
+
+    /// Like `carve`, but for `count` elements of `T`.
+    pub fn carve_as<T>(&mut self, name: impl fmt::Display, count: usize) -> &'pci mut [T] {
+        let region = self.carve(name, count * mem::size_of::<T>(), crate::CACHE_LINE_SIZE);
+        let (prefix, region, suffix) = unsafe { region.align_to_mut::<T>() };
+        assert!(prefix.is_empty());
+        assert!(suffix.is_empty());
+        region
+    }
+
+    /// Write canaries and poison. Called by the rank initializing the slot,
+    /// once every region is carved.
+    pub fn initialize(&self) {
+        for zone in self.zones.iter().chain(&self.unused()) {
+            unsafe {
+                std::ptr::write_bytes(self.base.add(zone.offset), zone.pattern, zone.size);
+            }
+            coherence::flush(self.bytes(zone));
+        }
+    }
+
+    /// Abort if any canary or poison was overwritten.
+    pub fn verify(&self) {
+        let violations = self.violations();
+        if !violations.is_empty() {
+            crate::abort(format!(
+                "slot guard zones overwritten: {}",
+                violations.join("; ")
+            ));
+        }
+    }
+
+    /// Descriptions of the zones whose canary or poison was overwritten.
+    fn violations(&self) -> Vec<String> {
+        self.zones
+            .iter()
+            .chain(&self.unused())
+            .filter_map(|zone| {
+                let bytes = self.bytes(zone);
+                coherence::invalidate(bytes);
+                bytes
+                    .iter()
+                    .position(|byte| *byte != zone.pattern)
+                    .map(|index| {
+                        format!(
+                            "{} (slot offset {:#x}, {} bytes): byte at {:#x} is {:#04x}, expected {:#04x}",
+                            zone.name,
+                            zone.offset,
+                            zone.size,
+                            zone.offset + index,
+                            bytes[index],
+                            zone.pattern,
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    fn zone(&mut self, kind: &str, region: &dyn fmt::Display, size: usize, pattern: u8) {
+        if cfg!(feature = "guards") && size > 0 {
+            let offset = self.offset;
+            self.zones.push(Zone {
+                name: format!("{} {}", kind, region),
+                offset,
+                size,
+                pattern,
+            });
+        }
+        self.take(size);
+    }
+
+    /// Up to a page of the slot past the layout, which no region may spill
+    /// into. Poisoning the whole tail would rewrite most of a small
+    /// collective's slot every call.
+    fn unused(&self) -> Option<Zone> {
+        (cfg!(feature = "guards") && self.offset < self.capacity).then(|| Zone {
+            name: String::from("unused slot tail"),
+            offset: self.offset,
+            size: (self.capacity - self.offset).min(crate::PAGE_SIZE),
+            pattern: POISON,
+        })
+    }
+
+    fn take(&mut self, size: usize) -> &'pci mut [u8] {
+        if self.base.is_null() {
+            self.offset = self.offset.saturating_add(size);
+            return &mut [];
+        }
+
+        assert!(
+            size <= self.capacity - self.offset,
+            "Slot too small for layout"
+        );
+        let region = unsafe { std::slice::from_raw_parts_mut(self.base.add(self.offset), size) };
+        self.offset += size;
+        region
+    }
+
+    /// Zones never overlap carved regions, so may be accessed alongside them.
+    fn bytes(&self, zone: &Zone) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.base.add(zone.offset), zone.size) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Only accessed as bytes of the slot.
+    #[allow(dead_code)]
+    #[derive(Copy, Clone)]
+    #[repr(align(4096))]
+    struct Page([u8; crate::PAGE_SIZE]);
+
+    /// Page aligned, zeroed slot of `pages` pages.
+    fn slot(pages: usize) -> Vec<Page> {
+        vec![Page([0; crate::PAGE_SIZE]); pages]
+    }
+
+    fn bytes(slot: &mut [Page]) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(slot.as_mut_ptr().cast(), mem::size_of_val(slot)) }
+    }
+
+    fn carve<'pci>(layout: &mut Layout<'pci>) -> (&'pci mut [u8], &'pci mut [u64], &'pci mut [u8]) {
+        let first = layout.carve("first", 10, 1);
+        let second = layout.carve_as::<u64>("second", 3);
+        let third = layout.carve("third", 100, 256);
+        (first, second, third)
+    }
+
+    #[test]
+    fn carves_aligned_disjoint_regions() {
+        let mut slot = slot(16);
+        let base = bytes(&mut slot).as_ptr() as usize;
+        let mut layout = Layout::new(bytes(&mut slot));
+        let (first, second, third) = carve(&mut layout);
+
+        assert_eq!(first.len(), 10);
+        assert_eq!(second.len(), 3);
+        assert_eq!(third.len(), 100);
+
+        let second_offset = second.as_ptr() as usize - base;
+        let third_offset = third.as_ptr() as usize - base;
+        assert_eq!(first.as_ptr() as usize, base);
+        assert_eq!(second_offset % crate::CACHE_LINE_SIZE, 0);
+        assert_eq!(third_offset % 256, 0);
+
+        // Each region is followed by its guard zone
+        assert!(second_offset >= 10 + GUARD_SIZE);
+        assert!(third_offset >= second_offset + 24 + GUARD_SIZE);
+        assert_eq!(layout.size(), third_offset + 100 + GUARD_SIZE);
+    }
+
+    #[test]
+    fn measure_matches_carving() {
+        let mut slot = slot(16);
+        let mut layout = Layout::new(bytes(&mut slot));
+        carve(&mut layout);
+
+        let mut measure = Layout::measure();
+        let (first, second, third) = carve(&mut measure);
+        assert!(first.is_empty() && second.is_empty() && third.is_empty());
+        assert_eq!(measure.size(), layout.size());
+    }
+
+    #[test]
+    #[should_panic(expected = "Slot too small")]
+    fn rejects_oversized_region() {
+        let mut slot = slot(1);
+        Layout::new(bytes(&mut slot)).carve("data", 2 * crate::PAGE_SIZE, 1);
+    }
+
+    #[cfg(feature = "guards")]
+    #[test]
+    fn detects_overwritten_zones() {
+        let mut slot = slot(16);
+        let mut layout = Layout::new(bytes(&mut slot));
+        let (first, _, third) = carve(&mut layout);
+        layout.initialize();
+        assert!(layout.violations().is_empty());
+
+        // Past the end of a region, into its guard zone
+        unsafe { *first.as_mut_ptr().add(first.len()) = 0 };
+        // Into the padding before a region
+        unsafe { *third.as_mut_ptr().sub(1) = 0 };
+
+        let violations = layout.violations();
+        assert_eq!(violations.len(), 2);
+        assert!(violations[0].starts_with("guard after first"));
+        assert!(violations[1].starts_with("padding before third"));
+    }
+
+    #[cfg(feature = "guards")]
+    #[test]
+    fn poisons_one_page_of_tail() {
+        let mut slot = slot(16);
+        let mut layout = Layout::new(bytes(&mut slot));
+        carve(&mut layout);
+        let size = layout.size();
+        layout.initialize();
+        drop(layout);
+
+        let slot = bytes(&mut slot);
+        assert!(slot[size..size + crate::PAGE_SIZE]
+            .iter()
+            .all(|byte| *byte == POISON));
+        assert!(slot[size + crate::PAGE_SIZE..]
+            .iter()
+            .all(|byte| *byte == 0));
+    }
+}
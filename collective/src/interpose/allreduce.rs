@@ -0,0 +1,319 @@
+use std::cmp;
+use std::ffi;
+use std::mem;
+
+use crate::allreduce;
+use crate::allreduce::Reduction;
+use crate::interpose::datatype::Pair;
+use crate::interpose::datatype::Predefined;
+use crate::interpose::flatten;
+use crate::interpose::flatten::Derived;
+use crate::interpose::flatten::Flat;
+use crate::interpose::handle;
+use crate::interpose::op;
+use crate::interpose::pmpi;
+use crate::interpose::signature;
+use crate::interpose::Communicator;
+use crate::metrics;
+use crate::signature::Collective;
+use crate::wait;
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Allreduce(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: ffi::c_int,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    match usize::try_from(count) {
+        Ok(count) => entry(buffer_send, buffer_receive, count, datatype, op, comm),
+        // Let MPI report negative counts
+        Err(_) => pmpi::PMPI_Allreduce(buffer_send, buffer_receive, count, datatype, op, comm),
+    }
+}
+
+/// Large-count `MPI_Allreduce`, from MPI 4.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Allreduce_c(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: mpi::ffi::MPI_Count,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    match usize::try_from(count) {
+        Ok(count) => entry(buffer_send, buffer_receive, count, datatype, op, comm),
+        Err(_) => pmpi::PMPI_Allreduce_c.expect("MPI_Allreduce_c requires MPI 4")(
+            buffer_send,
+            buffer_receive,
+            count,
+            datatype,
+            op,
+            comm,
+        ),
+    }
+}
+
+unsafe fn entry(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    metrics::reset();
+    wait::enter("MPI_Allreduce");
+    crate::signature::enter(signature::new(
+        Collective::Allreduce,
+        count,
+        datatype,
+        Some(op),
+        None,
+    ));
+    let error = metrics::time!(metrics::timers::TOTAL, {
+        match dispatch(buffer_send, buffer_receive, count, datatype, op, comm) {
+            Some(error) => error,
+            None => allreduce_derived(buffer_send, buffer_receive, count, datatype, op, comm),
+        }
+    });
+    metrics::dump();
+    error
+}
+
+/// Allreduce over a predefined `datatype`, or `None` if unsupported.
+unsafe fn dispatch(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> Option<ffi::c_int> {
+    let error = if f32::matches(datatype) {
+        allreduce::<f32>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else if f64::matches(datatype) {
+        allreduce::<f64>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else if i32::matches(datatype) {
+        allreduce::<i32>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else if i8::matches(datatype) {
+        allreduce::<i8>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else if Pair::<f32>::matches(datatype) {
+        allreduce::<Pair<f32>>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else if Pair::<f64>::matches(datatype) {
+        allreduce::<Pair<f64>>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else if Pair::<ffi::c_int>::matches(datatype) {
+        allreduce::<Pair<ffi::c_int>>(buffer_send, buffer_receive, count, datatype, op, comm)
+    } else {
+        return None;
+    };
+    Some(error)
+}
+
+/// Reduces a derived datatype as the predefined datatype it is made of, in
+/// place if contiguous, and otherwise gathered through the slot. Derived
+/// datatypes mixing predefined ones are left to MPI, as are user operators,
+/// which must be called with the datatype and count they were given.
+unsafe fn allreduce_derived(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    if op::lookup(op).is_some() {
+        return forward(buffer_send, buffer_receive, count, datatype, op, comm);
+    }
+
+    let flat = flatten::get(datatype);
+    let (flat, base, extent) = match flat.as_ref().and_then(|flat| Some((flat, flat.base()?))) {
+        Some((flat, (base, extent))) => (flat, base, extent),
+        None => return forward(buffer_send, buffer_receive, count, datatype, op, comm),
+    };
+
+    if flat.contiguous() {
+        let base_count = count * flat.size() / extent;
+        return dispatch(buffer_send, buffer_receive, base_count, base, op, comm)
+            .unwrap_or_else(|| forward(buffer_send, buffer_receive, count, datatype, op, comm));
+    }
+
+    dispatch_derived(
+        buffer_send.cast(),
+        buffer_receive.cast(),
+        count,
+        flat,
+        base,
+        op,
+        comm,
+    )
+    .unwrap_or_else(|| forward(buffer_send, buffer_receive, count, datatype, op, comm))
+}
+
+/// Allreduce over `count` elements of a non-contiguous derived datatype made
+/// of the predefined `base`, or `None` if unsupported.
+unsafe fn dispatch_derived(
+    send: *const u8,
+    receive: *mut u8,
+    count: usize,
+    flat: &Flat,
+    base: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> Option<ffi::c_int> {
+    if f32::matches(base) {
+        allreduce_gathered::<f32>(send, receive, count, flat, base, op, comm)
+    } else if f64::matches(base) {
+        allreduce_gathered::<f64>(send, receive, count, flat, base, op, comm)
+    } else if i32::matches(base) {
+        allreduce_gathered::<i32>(send, receive, count, flat, base, op, comm)
+    } else if i8::matches(base) {
+        allreduce_gathered::<i8>(send, receive, count, flat, base, op, comm)
+    } else if Pair::<f32>::matches(base) {
+        allreduce_gathered::<Pair<f32>>(send, receive, count, flat, base, op, comm)
+    } else if Pair::<f64>::matches(base) {
+        allreduce_gathered::<Pair<f64>>(send, receive, count, flat, base, op, comm)
+    } else if Pair::<ffi::c_int>::matches(base) {
+        allreduce_gathered::<Pair<ffi::c_int>>(send, receive, count, flat, base, op, comm)
+    } else {
+        None
+    }
+}
+
+/// Gathers each rank's elements straight into its contribution, and scatters
+/// the result straight out of the slot, so always uses the multiple buffer
+/// algorithm. Returns `None` if `op` is not defined on `T`, or the elements
+/// do not fit in a slot.
+unsafe fn allreduce_gathered<T: Predefined>(
+    send: *const u8,
+    receive: *mut u8,
+    count: usize,
+    flat: &Flat,
+    base: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> Option<ffi::c_int> {
+    let operator = Operator::new::<T>(base, op)?;
+    let group = Communicator(comm).group();
+    let len = count * flat.size() / mem::size_of::<T>();
+    if allreduce::required_multiple::<T>(len, &group) > crate::pci().slot_size() {
+        return None;
+    }
+
+    let local = Derived {
+        send,
+        receive,
+        count,
+        flat,
+    };
+
+    let valid = allreduce::allreduce_multiple::<T, _>(local, operator, &group);
+    Some(crate::interpose::status(valid))
+}
+
+/// Operators reduced in shared memory.
+#[derive(Copy, Clone)]
+enum Operator {
+    /// `MPI_SUM`, reduced by `MpiType`.
+    Sum,
+    /// `MPI_MAXLOC` (`Greater`) or `MPI_MINLOC` (`Less`) on pair datatypes.
+    Locate(cmp::Ordering),
+    /// Operators registered with `MPI_Op_create`.
+    User(op::User, mpi::ffi::MPI_Datatype),
+}
+
+impl Operator {
+    /// Operator `op` over `T`, or `None` if `op` is not implemented, or not
+    /// defined on `T`.
+    fn new<T: Predefined>(datatype: mpi::ffi::MPI_Datatype, op: mpi::ffi::MPI_Op) -> Option<Self> {
+        if let Some(user) = op::lookup(op) {
+            return Some(Operator::User(user, datatype));
+        }
+
+        match (op as usize, T::PAIR) {
+            (op, false) if op == unsafe { mpi::ffi::RSMPI_SUM } as usize => Some(Operator::Sum),
+            (op, true) if op == *handle::MAXLOC => Some(Operator::Locate(cmp::Ordering::Greater)),
+            (op, true) if op == *handle::MINLOC => Some(Operator::Locate(cmp::Ordering::Less)),
+            _ => None,
+        }
+    }
+
+    fn commute(&self) -> bool {
+        match self {
+            Operator::Sum | Operator::Locate(_) => true,
+            Operator::User(user, _) => user.commute(),
+        }
+    }
+}
+
+impl<T: Predefined> Reduction<T> for Operator {
+    fn zeroed(&self) -> bool {
+        matches!(self, Operator::Sum)
+    }
+
+    fn apply(&self, shared: &mut [T], other: &[T]) {
+        match self {
+            Operator::Sum => T::sum_slice_mut(shared, other),
+            Operator::Locate(extremum) => T::locate_slice_mut(shared, other, *extremum),
+            Operator::User(user, datatype) => user.apply(*datatype, other, shared),
+        }
+    }
+}
+
+unsafe fn allreduce<T: Predefined>(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    let operator = match Operator::new::<T>(datatype, op) {
+        Some(operator) => operator,
+        // Let MPI apply other predefined operators, or report them undefined
+        // on the datatype
+        None => return forward(buffer_send, buffer_receive, count, datatype, op, comm),
+    };
+
+    // Contributions are reduced in arrival order, which only commutative
+    // operators tolerate, so leave the rest to MPI
+    if !allreduce::ordered() && !operator.commute() {
+        return forward(buffer_send, buffer_receive, count, datatype, op, comm);
+    }
+
+    // Leave buffers too large for a slot to MPI
+    let group = Communicator(comm).group();
+    if allreduce::required::<T, _>(count, &operator, &group) > crate::pci().slot_size() {
+        return forward(buffer_send, buffer_receive, count, datatype, op, comm);
+    }
+
+    let buffer_send = std::slice::from_raw_parts(buffer_send as *const T, count);
+    let buffer_receive = std::slice::from_raw_parts_mut(buffer_receive as *mut T, count);
+    let valid = allreduce::reduce(buffer_send, buffer_receive, operator, &group);
+    crate::interpose::status(valid)
+}
+
+unsafe fn forward(
+    buffer_send: *const ffi::c_void,
+    buffer_receive: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: mpi::ffi::MPI_Op,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    // Forward to `PMPI_Allreduce`, or `PMPI_Allreduce_c` if `count` needs it
+    match ffi::c_int::try_from(count) {
+        Ok(count) => pmpi::PMPI_Allreduce(buffer_send, buffer_receive, count, datatype, op, comm),
+        Err(_) => pmpi::PMPI_Allreduce_c.expect("MPI_Allreduce_c requires MPI 4")(
+            buffer_send,
+            buffer_receive,
+            count as mpi::ffi::MPI_Count,
+            datatype,
+            op,
+            comm,
+        ),
+    }
+}
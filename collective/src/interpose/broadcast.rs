@@ -0,0 +1,110 @@
+use std::ffi;
+
+use crate::broadcast;
+use crate::interpose::flatten;
+use crate::interpose::flatten::Derived;
+use crate::interpose::pmpi;
+use crate::interpose::signature;
+use crate::interpose::Communicator;
+use crate::signature::Collective;
+use crate::wait;
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Bcast(
+    buffer: *mut ffi::c_void,
+    count: ffi::c_int,
+    datatype: mpi::ffi::MPI_Datatype,
+    root: ffi::c_int,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    match usize::try_from(count) {
+        Ok(count) => entry(buffer, count, datatype, root, comm),
+        // Let MPI report negative counts
+        Err(_) => pmpi::PMPI_Bcast(buffer, count, datatype, root, comm),
+    }
+}
+
+/// Large-count `MPI_Bcast`, from MPI 4.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Bcast_c(
+    buffer: *mut ffi::c_void,
+    count: mpi::ffi::MPI_Count,
+    datatype: mpi::ffi::MPI_Datatype,
+    root: ffi::c_int,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    match usize::try_from(count) {
+        Ok(count) => entry(buffer, count, datatype, root, comm),
+        Err(_) => pmpi::PMPI_Bcast_c.expect("MPI_Bcast_c requires MPI 4")(
+            buffer, count, datatype, root, comm,
+        ),
+    }
+}
+
+unsafe fn entry(
+    buffer: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    root: ffi::c_int,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    wait::enter("MPI_Bcast");
+    crate::signature::enter(signature::new(
+        Collective::Bcast,
+        count,
+        datatype,
+        None,
+        Some(root),
+    ));
+
+    let flat = match flatten::get(datatype) {
+        Some(flat) => flat,
+        None => return forward(buffer, count, datatype, root, comm),
+    };
+    // Leave buffers too large for a slot to MPI
+    let group = Communicator(comm).group();
+    if broadcast::required(count * flat.size(), &group) > crate::pci().slot_size() {
+        return forward(buffer, count, datatype, root, comm);
+    }
+
+    let valid = match flat.contiguous() {
+        true => broadcast::broadcast(
+            std::slice::from_raw_parts_mut(buffer.cast::<u8>(), count * flat.size()),
+            root,
+            &group,
+        ),
+        // Gathered straight into the slot by the root, and scattered straight
+        // out of it by the others
+        false => broadcast::broadcast(
+            Derived {
+                send: buffer.cast(),
+                receive: buffer.cast(),
+                count,
+                flat: &flat,
+            },
+            root,
+            &group,
+        ),
+    };
+    crate::interpose::status(valid)
+}
+
+unsafe fn forward(
+    buffer: *mut ffi::c_void,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    root: ffi::c_int,
+    comm: mpi::ffi::MPI_Comm,
+) -> ffi::c_int {
+    // Forward to `PMPI_Bcast`, or `PMPI_Bcast_c` if `count` needs it
+    match ffi::c_int::try_from(count) {
+        Ok(count) => pmpi::PMPI_Bcast(buffer, count, datatype, root, comm),
+        Err(_) => pmpi::PMPI_Bcast_c.expect("MPI_Bcast_c requires MPI 4")(
+            buffer,
+            count as mpi::ffi::MPI_Count,
+            datatype,
+            root,
+            comm,
+        ),
+    }
+}
@@ -0,0 +1,176 @@
+//! Datatypes the interposer reduces itself, by their MPI handles.
+
+use std::cmp;
+use std::ffi;
+
+use crate::datatype::MpiType;
+use crate::interpose::handle;
+
+pub(crate) trait Predefined: MpiType {
+    /// Whether this is a (value, index) pair, the only datatypes supporting
+    /// `MPI_MAXLOC` and `MPI_MINLOC`, and no other operator.
+    const PAIR: bool = false;
+
+    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool;
+
+    /// Element-wise `shared = other op shared`, where `op` keeps the value
+    /// ordered `extremum` (`Greater` for `MPI_MAXLOC`, `Less` for
+    /// `MPI_MINLOC`) with respect to the other, and the lower index on ties.
+    /// NaN counts as the extremum.
+    fn locate_slice_mut(_shared: &mut [Self], _other: &[Self], _extremum: cmp::Ordering) {
+        unreachable!("MPI_MAXLOC and MPI_MINLOC are only defined on pair datatypes")
+    }
+}
+
+impl Predefined for f32 {
+    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
+        unsafe { datatype == mpi::ffi::RSMPI_FLOAT }
+    }
+}
+
+impl Predefined for f64 {
+    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
+        unsafe { datatype == mpi::ffi::RSMPI_DOUBLE }
+    }
+}
+
+impl Predefined for i8 {
+    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
+        unsafe { datatype == mpi::ffi::RSMPI_INT8_T || datatype as usize == *handle::SIGNED_CHAR }
+    }
+}
+
+impl Predefined for i32 {
+    fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
+        unsafe { datatype == mpi::ffi::RSMPI_INT32_T || datatype as usize == *handle::INT }
+    }
+}
+
+/// Layout of `MPI_FLOAT_INT`, `MPI_DOUBLE_INT` and `MPI_2INT`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Pair<V> {
+    value: V,
+    index: ffi::c_int,
+}
+
+impl<V: Copy + PartialOrd> Pair<V> {
+    /// NaN counts as the extremum for both operators, so a NaN from any rank
+    /// reaches the result whatever order ranks are reduced in, with the lowest
+    /// index of those holding it. A plain comparison would keep or drop it
+    /// depending on that order.
+    fn locate_mut(&mut self, other: &Self, extremum: cmp::Ordering) {
+        let ordering = match (self.nan(), other.nan()) {
+            (false, false) => other.value.partial_cmp(&self.value).unwrap(),
+            (true, true) => cmp::Ordering::Equal,
+            (false, true) => extremum,
+            (true, false) => extremum.reverse(),
+        };
+        match ordering {
+            cmp::Ordering::Equal => self.index = cmp::min(self.index, other.index),
+            ordering if ordering == extremum => *self = *other,
+            _ => (),
+        }
+    }
+
+    fn nan(&self) -> bool {
+        self.value.partial_cmp(&self.value).is_none()
+    }
+
+    fn locate_slice_mut(shared: &mut [Self], other: &[Self], extremum: cmp::Ordering) {
+        shared
+            .iter_mut()
+            .zip(other)
+            .for_each(|(shared, other)| shared.locate_mut(other, extremum));
+    }
+}
+
+macro_rules! pair {
+    ($value:ty, $handle:ident, $name:literal) => {
+        impl MpiType for Pair<$value> {
+            fn sum_slice_mut(_: &mut [Self], _: &[Self]) {
+                unreachable!(concat!("MPI_SUM is not defined on ", $name))
+            }
+        }
+
+        impl Predefined for Pair<$value> {
+            const PAIR: bool = true;
+
+            fn matches(datatype: mpi::ffi::MPI_Datatype) -> bool {
+                datatype as usize == *handle::$handle
+            }
+
+            fn locate_slice_mut(shared: &mut [Self], other: &[Self], extremum: cmp::Ordering) {
+                Pair::locate_slice_mut(shared, other, extremum);
+            }
+        }
+    };
+}
+
+pair!(f32, FLOAT_INT, "MPI_FLOAT_INT");
+pair!(f64, DOUBLE_INT, "MPI_DOUBLE_INT");
+pair!(ffi::c_int, TWO_INT, "MPI_2INT");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair<V>(value: V, index: ffi::c_int) -> Pair<V> {
+        Pair { value, index }
+    }
+
+    /// `contributions` reduced in order with `extremum`, as (value, index).
+    fn locate<V: Copy + PartialOrd>(
+        contributions: &[(V, ffi::c_int)],
+        extremum: cmp::Ordering,
+    ) -> (V, ffi::c_int) {
+        let mut shared = [pair(contributions[0].0, contributions[0].1)];
+        for (value, index) in &contributions[1..] {
+            Pair::locate_slice_mut(&mut shared, &[pair(*value, *index)], extremum);
+        }
+        (shared[0].value, shared[0].index)
+    }
+
+    #[test]
+    fn maxloc_minloc() {
+        let contributions = [(3, 0), (7, 1), (-2, 2), (5, 3)];
+        assert_eq!(locate(&contributions, cmp::Ordering::Greater), (7, 1));
+        assert_eq!(locate(&contributions, cmp::Ordering::Less), (-2, 2));
+
+        let contributions = [(1.5_f32, 4), (-0.5, 2), (2.5, 9)];
+        assert_eq!(locate(&contributions, cmp::Ordering::Greater), (2.5, 9));
+        assert_eq!(locate(&contributions, cmp::Ordering::Less), (-0.5, 2));
+    }
+
+    #[test]
+    fn ties_keep_lowest_index() {
+        for extremum in [cmp::Ordering::Greater, cmp::Ordering::Less] {
+            assert_eq!(locate(&[(1.0, 5), (1.0, 2), (1.0, 7)], extremum), (1.0, 2));
+            assert_eq!(locate(&[(1.0, 2), (1.0, 5)], extremum), (1.0, 2));
+        }
+        assert_eq!(
+            locate(&[(0, 3), (4, 6), (4, 1)], cmp::Ordering::Greater),
+            (4, 1)
+        );
+        assert_eq!(
+            locate(&[(0, 3), (-4, 1), (0, 0)], cmp::Ordering::Less),
+            (-4, 1)
+        );
+    }
+
+    #[test]
+    fn nan_propagates() {
+        for extremum in [cmp::Ordering::Greater, cmp::Ordering::Less] {
+            // First, last, or in between, with the lowest index of the NaNs
+            for contributions in [
+                [(f64::NAN, 4), (1.0, 0), (f64::NAN, 2)],
+                [(1.0, 0), (f64::NAN, 4), (f64::NAN, 2)],
+                [(1.0, 0), (f64::NAN, 2), (-1.0, 1)],
+            ] {
+                let (value, index) = locate(&contributions, extremum);
+                assert!(value.is_nan());
+                assert_eq!(index, 2);
+            }
+        }
+    }
+}
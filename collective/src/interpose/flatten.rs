@@ -0,0 +1,446 @@
+//! Flattening of derived datatypes into contiguous byte blocks.
+//!
+//! Datatypes are decoded with `MPI_Type_get_envelope` and
+//! `MPI_Type_get_contents` into the blocks making up one element, in type map
+//! order, and cached by handle until `MPI_Type_free`. Collectives then gather
+//! elements out of user buffers, and scatter them back, block by block.
+
+use std::collections::HashMap;
+use std::ffi;
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::checksum;
+use crate::coherence;
+use crate::interpose::pmpi;
+use crate::local::Local;
+
+static CACHE: Lazy<Mutex<HashMap<usize, Arc<Flat>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Bytes `offset..offset + len` of an element, relative to its start.
+#[derive(Copy, Clone)]
+struct Block {
+    offset: isize,
+    len: usize,
+}
+
+#[derive(Copy, Clone)]
+enum Base {
+    Empty,
+    One(mpi::ffi::MPI_Datatype, usize),
+    Mixed,
+}
+
+pub struct Flat {
+    blocks: Vec<Block>,
+    extent: usize,
+    size: usize,
+    base: Base,
+}
+
+// Handles are valid across threads
+unsafe impl Send for Flat {}
+unsafe impl Sync for Flat {}
+
+impl Flat {
+    /// Whether `count` elements occupy `count * size()` bytes with no gaps, so
+    /// buffers can be used as is.
+    pub fn contiguous(&self) -> bool {
+        match self.blocks.as_slice() {
+            [block] => block.offset == 0 && block.len == self.extent,
+            _ => false,
+        }
+    }
+
+    /// Bytes of one packed element.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Predefined datatype and its extent, if every block is made of it.
+    pub fn base(&self) -> Option<(mpi::ffi::MPI_Datatype, usize)> {
+        match self.base {
+            Base::One(datatype, extent) => Some((datatype, extent)),
+            Base::Empty | Base::Mixed => None,
+        }
+    }
+
+    /// Copy `count` elements out of `buffer` into `packed`.
+    pub unsafe fn gather(&self, buffer: *const u8, count: usize, packed: &mut [u8]) {
+        assert_eq!(packed.len(), count * self.size);
+
+        let mut packed = packed;
+        for element in 0..count {
+            let start = buffer.add(element * self.extent);
+            for block in &self.blocks {
+                let (head, tail) = mem::take(&mut packed).split_at_mut(block.len);
+                ptr::copy_nonoverlapping(start.offset(block.offset), head.as_mut_ptr(), block.len);
+                packed = tail;
+            }
+        }
+    }
+
+    /// Copy `count` elements out of `packed` into `buffer`.
+    pub unsafe fn scatter(&self, packed: &[u8], buffer: *mut u8, count: usize) {
+        assert_eq!(packed.len(), count * self.size);
+
+        let mut packed = packed;
+        for element in 0..count {
+            let start = buffer.add(element * self.extent);
+            for block in &self.blocks {
+                let (head, tail) = packed.split_at(block.len);
+                ptr::copy_nonoverlapping(head.as_ptr(), start.offset(block.offset), block.len);
+                packed = tail;
+            }
+        }
+    }
+
+    /// Append one element of `other` at `displacement` bytes.
+    fn push(&mut self, other: &Flat, displacement: isize) {
+        for block in &other.blocks {
+            self.push_block(block.offset + displacement, block.len);
+        }
+
+        self.base = match (self.base, other.base) {
+            (Base::Empty, base) => base,
+            (base, Base::Empty) => base,
+            (Base::One(left, extent), Base::One(right, _)) if left == right => {
+                Base::One(left, extent)
+            }
+            _ => Base::Mixed,
+        };
+    }
+
+    fn push_block(&mut self, offset: isize, len: usize) {
+        self.size += len;
+        match self.blocks.last_mut() {
+            Some(last) if last.offset + last.len as isize == offset => last.len += len,
+            _ => self.blocks.push(Block { offset, len }),
+        }
+    }
+}
+
+/// `count` elements of a derived datatype made of `T`, gathered straight into
+/// the slot out of `send`, and scattered straight out of it into `receive`,
+/// which is the same buffer in a broadcast.
+pub struct Derived<'a> {
+    pub send: *const u8,
+    pub receive: *mut u8,
+    pub count: usize,
+    pub flat: &'a Flat,
+}
+
+impl<T> Local<T> for Derived<'_> {
+    fn len(&self) -> usize {
+        self.count * self.flat.size() / mem::size_of::<T>()
+    }
+
+    unsafe fn write(&self, shared: &mut [T], checksums: Option<&mut [u32]>) {
+        self.flat.gather(self.send, self.count, bytes_mut(shared));
+        coherence::flush(shared);
+        if let Some(checksums) = checksums {
+            checksum::compute(shared, checksums);
+        }
+    }
+
+    unsafe fn read(&mut self, name: &str, shared: &[T], checksums: Option<&[u32]>) -> bool {
+        coherence::invalidate(shared);
+        let valid = checksums.is_none_or(|checksums| checksum::verify(name, shared, checksums, 0));
+        self.flat.scatter(bytes(shared), self.receive, self.count);
+        valid
+    }
+}
+
+fn bytes<T>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr().cast(), mem::size_of_val(data)) }
+}
+
+fn bytes_mut<T>(data: &mut [T]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr().cast(), mem::size_of_val(data)) }
+}
+
+/// Flattened `datatype`, or `None` if it uses an unsupported constructor.
+pub fn get(datatype: mpi::ffi::MPI_Datatype) -> Option<Arc<Flat>> {
+    if let Some(flat) = CACHE.lock().unwrap().get(&(datatype as usize)) {
+        return Some(flat.clone());
+    }
+
+    let flat = Arc::new(unsafe { decode(datatype)? });
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(datatype as usize, flat.clone());
+    Some(flat)
+}
+
+unsafe fn decode(datatype: mpi::ffi::MPI_Datatype) -> Option<Flat> {
+    // Displacements are relative to the buffer, as `MPI_Pack` reads them, so
+    // the lower bound moves no block: elements, here and in each constituent
+    // datatype, start `extent` apart from the buffer whatever it is.
+    let (mut lb, mut extent) = (0, 0);
+    mpi::ffi::MPI_Type_get_extent(datatype, &mut lb, &mut extent);
+
+    let mut flat = Flat {
+        blocks: Vec::new(),
+        extent: extent as usize,
+        size: 0,
+        base: Base::Empty,
+    };
+
+    let (mut integers, mut addresses, mut datatypes, mut combiner) = (0, 0, 0, 0);
+    mpi::ffi::MPI_Type_get_envelope(
+        datatype,
+        &mut integers,
+        &mut addresses,
+        &mut datatypes,
+        &mut combiner,
+    );
+
+    // Predefined datatypes span their extent, including the padding of pairs
+    if combiner as u32 == mpi::ffi::MPI_COMBINER_NAMED {
+        flat.push_block(0, extent as usize);
+        flat.base = Base::One(datatype, extent as usize);
+        return Some(flat);
+    }
+
+    let mut integers = vec![0; integers as usize];
+    let mut addresses = vec![0; addresses as usize];
+    let mut datatypes = vec![mpi::ffi::RSMPI_DATATYPE_NULL; datatypes as usize];
+    mpi::ffi::MPI_Type_get_contents(
+        datatype,
+        integers.len() as ffi::c_int,
+        addresses.len() as ffi::c_int,
+        datatypes.len() as ffi::c_int,
+        integers.as_mut_ptr(),
+        addresses.as_mut_ptr(),
+        datatypes.as_mut_ptr(),
+    );
+
+    let children = datatypes
+        .iter()
+        .map(|datatype| decode(*datatype))
+        .collect::<Option<Vec<_>>>();
+
+    // Constituent datatypes are copies, which must be freed unless predefined
+    for mut datatype in datatypes {
+        let (mut integers, mut addresses, mut datatypes, mut combiner) = (0, 0, 0, 0);
+        mpi::ffi::MPI_Type_get_envelope(
+            datatype,
+            &mut integers,
+            &mut addresses,
+            &mut datatypes,
+            &mut combiner,
+        );
+        if combiner as u32 != mpi::ffi::MPI_COMBINER_NAMED {
+            pmpi::PMPI_Type_free(&mut datatype);
+        }
+    }
+
+    let children = children?;
+    let child = children.first()?;
+    let count = |index: usize| integers[index] as usize;
+
+    match combiner as u32 {
+        mpi::ffi::MPI_COMBINER_DUP => flat.push(child, 0),
+        mpi::ffi::MPI_COMBINER_RESIZED => flat.push(child, 0),
+        mpi::ffi::MPI_COMBINER_CONTIGUOUS => {
+            for element in 0..count(0) {
+                flat.push(child, (element * child.extent) as isize);
+            }
+        }
+        // count, blocklength, stride (in elements)
+        mpi::ffi::MPI_COMBINER_VECTOR => {
+            for block in 0..count(0) {
+                for element in 0..count(1) {
+                    let index = block as isize * integers[2] as isize + element as isize;
+                    flat.push(child, index * child.extent as isize);
+                }
+            }
+        }
+        // count, blocklength; stride (in bytes)
+        mpi::ffi::MPI_COMBINER_HVECTOR => {
+            for block in 0..count(0) {
+                for element in 0..count(1) {
+                    let offset = block as isize * addresses[0] + (element * child.extent) as isize;
+                    flat.push(child, offset);
+                }
+            }
+        }
+        // count, blocklengths[count], displacements[count] (in elements)
+        mpi::ffi::MPI_COMBINER_INDEXED => {
+            for block in 0..count(0) {
+                let displacement = integers[1 + count(0) + block] as isize;
+                for element in 0..count(1 + block) {
+                    let index = displacement + element as isize;
+                    flat.push(child, index * child.extent as isize);
+                }
+            }
+        }
+        // count, blocklengths[count]; displacements[count] (in bytes)
+        mpi::ffi::MPI_COMBINER_HINDEXED => {
+            for (block, displacement) in addresses.iter().enumerate() {
+                for element in 0..count(1 + block) {
+                    let offset = displacement + (element * child.extent) as isize;
+                    flat.push(child, offset);
+                }
+            }
+        }
+        // count, blocklength, displacements[count] (in elements)
+        mpi::ffi::MPI_COMBINER_INDEXED_BLOCK => {
+            for block in 0..count(0) {
+                let displacement = integers[2 + block] as isize;
+                for element in 0..count(1) {
+                    let index = displacement + element as isize;
+                    flat.push(child, index * child.extent as isize);
+                }
+            }
+        }
+        // count, blocklength; displacements[count] (in bytes)
+        mpi::ffi::MPI_COMBINER_HINDEXED_BLOCK => {
+            for displacement in &addresses {
+                for element in 0..count(1) {
+                    let offset = displacement + (element * child.extent) as isize;
+                    flat.push(child, offset);
+                }
+            }
+        }
+        // count, blocklengths[count]; displacements[count]; datatypes[count]
+        mpi::ffi::MPI_COMBINER_STRUCT => {
+            for (block, child) in children.iter().enumerate() {
+                for element in 0..count(1 + block) {
+                    let offset = addresses[block] + (element * child.extent) as isize;
+                    flat.push(child, offset);
+                }
+            }
+        }
+        _ => return None,
+    }
+
+    Some(flat)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Type_free(datatype: *mut mpi::ffi::MPI_Datatype) -> ffi::c_int {
+    // The handle may be reused by the next datatype created
+    CACHE.lock().unwrap().remove(&(*datatype as usize));
+    pmpi::PMPI_Type_free(datatype)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    /// Gather and scatter `count` elements of `datatype` out of and into a
+    /// buffer of `len` ints, checking both against `MPI_Pack` and `MPI_Unpack`,
+    /// then free `datatype`.
+    unsafe fn check(mut datatype: mpi::ffi::MPI_Datatype, count: usize, len: usize) {
+        mpi::ffi::MPI_Type_commit(&mut datatype);
+        let flat = get(datatype).unwrap();
+
+        let mut size = 0;
+        mpi::ffi::MPI_Type_size(datatype, &mut size);
+        assert_eq!(flat.size(), size as usize);
+
+        let buffer = (0..len as i32).collect::<Vec<_>>();
+        let mut packed = vec![0; count * flat.size()];
+        flat.gather(buffer.as_ptr().cast(), count, &mut packed);
+
+        let mut expected = vec![0_u8; packed.len()];
+        let mut position = 0;
+        mpi::ffi::MPI_Pack(
+            buffer.as_ptr().cast(),
+            count as ffi::c_int,
+            datatype,
+            expected.as_mut_ptr().cast(),
+            expected.len() as ffi::c_int,
+            &mut position,
+            mpi::ffi::RSMPI_COMM_WORLD,
+        );
+        assert_eq!(position as usize, expected.len());
+        assert_eq!(packed, expected);
+
+        let mut scattered = vec![-1; len];
+        flat.scatter(&packed, scattered.as_mut_ptr().cast(), count);
+
+        let mut unpacked = vec![-1; len];
+        let mut position = 0;
+        mpi::ffi::MPI_Unpack(
+            expected.as_ptr().cast(),
+            expected.len() as ffi::c_int,
+            &mut position,
+            unpacked.as_mut_ptr().cast(),
+            count as ffi::c_int,
+            datatype,
+            mpi::ffi::RSMPI_COMM_WORLD,
+        );
+        assert_eq!(scattered, unpacked);
+
+        MPI_Type_free(&mut datatype);
+    }
+
+    /// Three blocks of two ints, every four ints.
+    unsafe fn vector() -> mpi::ffi::MPI_Datatype {
+        let mut vector = mpi::ffi::RSMPI_DATATYPE_NULL;
+        mpi::ffi::MPI_Type_vector(3, 2, 4, mpi::ffi::RSMPI_INT32_T, &mut vector);
+        vector
+    }
+
+    #[test]
+    #[ignore = "needs an MPI runtime, run by tests/mpi.sh"]
+    fn vector_matches_pack() {
+        unsafe {
+            assert_eq!(pmpi::PMPI_Init(ptr::null_mut(), ptr::null_mut()), 0);
+            check(vector(), 3, 64);
+            assert_eq!(pmpi::PMPI_Finalize(), 0);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an MPI runtime, run by tests/mpi.sh"]
+    fn indexed_matches_pack() {
+        unsafe {
+            assert_eq!(pmpi::PMPI_Init(ptr::null_mut(), ptr::null_mut()), 0);
+
+            // Out of order, so the lower bound is that of the second block
+            let lengths = [3, 1, 2];
+            let displacements = [5, 1, 9];
+            let mut indexed = mpi::ffi::RSMPI_DATATYPE_NULL;
+            mpi::ffi::MPI_Type_indexed(
+                3,
+                lengths.as_ptr(),
+                displacements.as_ptr(),
+                mpi::ffi::RSMPI_INT32_T,
+                &mut indexed,
+            );
+            check(indexed, 3, 64);
+
+            assert_eq!(pmpi::PMPI_Finalize(), 0);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an MPI runtime, run by tests/mpi.sh"]
+    fn resized_matches_pack() {
+        unsafe {
+            assert_eq!(pmpi::PMPI_Init(ptr::null_mut(), ptr::null_mut()), 0);
+
+            // Lower bounds before and after the data, and extents wider and
+            // narrower than it, so elements are spaced apart and interleaved
+            for (lb, extent, count) in [(-8, 64, 3), (4, 48, 3), (0, 8, 2)] {
+                let mut vector = vector();
+                let mut resized = mpi::ffi::RSMPI_DATATYPE_NULL;
+                mpi::ffi::MPI_Type_create_resized(vector, lb, extent, &mut resized);
+                check(resized, count, 64);
+                MPI_Type_free(&mut vector);
+            }
+
+            assert_eq!(pmpi::PMPI_Finalize(), 0);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Predefined handles that rsmpi does not export.
+//!
+//! Open MPI defines each as the address of a global object, and MPICH (and
+//! derivatives like Intel MPI and MVAPICH) as an integer constant, so handles
+//! are compared as `usize`, after casting the `MPI_Datatype` or `MPI_Op` with
+//! `as usize`. Which one applies follows from the width of `MPI_Datatype` in
+//! the `mpi.h` that rsmpi was built against.
+
+use std::ffi;
+use std::mem;
+
+use once_cell::sync::Lazy;
+
+pub static INT: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_int\0", 0x4c00_0405));
+pub static SIGNED_CHAR: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_signed_char\0", 0x4c00_0118));
+pub static FLOAT_INT: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_float_int\0", 0x8c00_0000));
+pub static DOUBLE_INT: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_double_int\0", 0x8c00_0001));
+pub static TWO_INT: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_2int\0", 0x4c00_0816));
+
+pub static MINLOC: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_op_minloc\0", 0x5800_000b));
+pub static MAXLOC: Lazy<usize> = Lazy::new(|| resolve(b"ompi_mpi_op_maxloc\0", 0x5800_000c));
+
+/// MPICH's `constant`, sign extended as `MPI_Datatype` and `MPI_Op` are
+/// `int`, or else the address of Open MPI's `symbol`.
+fn resolve(symbol: &[u8], constant: u32) -> usize {
+    if mem::size_of::<mpi::ffi::MPI_Datatype>() == mem::size_of::<ffi::c_int>() {
+        return constant as i32 as usize;
+    }
+
+    let symbol = ffi::CStr::from_bytes_with_nul(symbol).unwrap();
+    let address = unsafe { libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr()) };
+    assert!(!address.is_null(), "Missing Open MPI handle {:?}", symbol);
+    address as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ptr;
+
+    use mpi::traits::Communicator as _;
+
+    use super::*;
+
+    const NAMES: [&str; 7] = [
+        "MPI_INT",
+        "MPI_SIGNED_CHAR",
+        "MPI_FLOAT_INT",
+        "MPI_DOUBLE_INT",
+        "MPI_2INT",
+        "MPI_MAXLOC",
+        "MPI_MINLOC",
+    ];
+
+    /// Handles named by `NAMES`, as defined by `mpi.h`, from the library
+    /// `tests/mpi.sh` builds out of `tests/handle.c`.
+    fn expected() -> [usize; 7] {
+        let path = env::var("COLLECTIVE_TEST_HANDLES").expect("Run by tests/mpi.sh");
+        let path = ffi::CString::new(path).unwrap();
+        let mut handles = [0; 7];
+        unsafe {
+            let library = libc::dlopen(path.as_ptr(), libc::RTLD_NOW);
+            assert!(!library.is_null(), "Failed to load {:?}", path);
+            let function = libc::dlsym(library, b"collective_test_handles\0".as_ptr().cast());
+            assert!(!function.is_null(), "Missing collective_test_handles");
+            let function: unsafe extern "C" fn(*mut usize) = mem::transmute(function);
+            function(handles.as_mut_ptr());
+        }
+        handles
+    }
+
+    #[test]
+    #[ignore = "needs an MPI runtime, run by tests/mpi.sh"]
+    fn resolved_match_mpi_h() {
+        let resolved = [
+            *INT,
+            *SIGNED_CHAR,
+            *FLOAT_INT,
+            *DOUBLE_INT,
+            *TWO_INT,
+            *MAXLOC,
+            *MINLOC,
+        ];
+        for ((name, expected), resolved) in NAMES.iter().zip(expected()).zip(resolved) {
+            assert_eq!(resolved, expected, "Mismatched {}", name);
+        }
+    }
+
+    /// `MPI_MAXLOC` over `MPI_FLOAT_INT`, with each element's maximum on a
+    /// different rank.
+    #[test]
+    #[ignore = "needs an MPI runtime, run by tests/mpi.sh"]
+    fn allreduce_maxloc() {
+        #[repr(C)]
+        #[derive(Copy, Clone, Debug, Default, PartialEq)]
+        struct FloatInt {
+            value: f32,
+            index: ffi::c_int,
+        }
+
+        let [_, _, float_int, _, _, maxloc, _] = expected();
+
+        unsafe {
+            assert_eq!(
+                crate::interpose::MPI_Init(ptr::null_mut(), ptr::null_mut()),
+                0
+            );
+            let world = crate::interpose::Communicator(mpi::ffi::RSMPI_COMM_WORLD);
+            let (rank, size) = (world.rank(), world.size());
+
+            let count = size * 1024;
+            let send = (0..count)
+                .map(|i| FloatInt {
+                    value: ((i + rank) % size) as f32,
+                    index: rank,
+                })
+                .collect::<Vec<_>>();
+            let mut receive = vec![FloatInt::default(); count as usize];
+
+            let error = crate::interpose::allreduce::MPI_Allreduce(
+                send.as_ptr().cast(),
+                receive.as_mut_ptr().cast(),
+                count,
+                float_int as mpi::ffi::MPI_Datatype,
+                maxloc as mpi::ffi::MPI_Op,
+                mpi::ffi::RSMPI_COMM_WORLD,
+            );
+            assert_eq!(error, 0);
+
+            for (i, received) in (0..count).zip(&receive) {
+                let expected = FloatInt {
+                    value: (size - 1) as f32,
+                    index: (size - 1 - i % size + size) % size,
+                };
+                assert_eq!(*received, expected, "Mismatched element {}", i);
+            }
+
+            assert_eq!(crate::interpose::MPI_Finalize(), 0);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! MPI interposer, preloaded ahead of (or linked before) MPI.
+//!
+//! Each interposed collective on a supported datatype and operator runs the
+//! shared memory algorithms over a `Group` of the communicator's ranks, and
+//! everything else is forwarded to the profiling interface.
+
+mod allreduce;
+mod broadcast;
+mod datatype;
+mod flatten;
+mod handle;
+mod op;
+mod pmpi;
+mod signature;
+
+use std::ffi;
+
+use mpi::traits::Communicator as _;
+use once_cell::sync::Lazy;
+
+use crate::group::Group;
+use crate::kernel;
+use crate::liveness;
+use crate::metrics;
+use crate::segment::Segment;
+use crate::wait;
+
+struct Communicator(mpi::ffi::MPI_Comm);
+
+impl Communicator {
+    fn group(&self) -> Group {
+        Group {
+            rank: self.rank(),
+            size: self.size(),
+        }
+    }
+}
+
+unsafe impl mpi::traits::AsRaw for Communicator {
+    type Raw = mpi::ffi::MPI_Comm;
+    fn as_raw(&self) -> Self::Raw {
+        self.0
+    }
+}
+
+impl mpi::traits::Communicator for Communicator {}
+
+/// `MPI_SUCCESS`, or `MPI_ERR_OTHER` if checksums found data corrupted in
+/// shared memory.
+fn status(valid: bool) -> ffi::c_int {
+    match valid {
+        true => mpi::ffi::MPI_SUCCESS as ffi::c_int,
+        false => mpi::ffi::MPI_ERR_OTHER as ffi::c_int,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Init(
+    argc: *mut ffi::c_int,
+    argv: *mut *mut *mut ffi::c_char,
+) -> ffi::c_int {
+    initialize(|| pmpi::PMPI_Init(argc, argv))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Init_thread(
+    argc: *mut ffi::c_int,
+    argv: *mut *mut *mut ffi::c_char,
+    required: ffi::c_int,
+    provided: *mut ffi::c_int,
+) -> ffi::c_int {
+    initialize(|| pmpi::PMPI_Init_thread(argc, argv, required, provided))
+}
+
+/// Open shared memory before MPI starts, so no collective initializes it
+/// lazily, and start liveness once ranks are known.
+unsafe fn initialize<F: FnOnce() -> ffi::c_int>(init: F) -> ffi::c_int {
+    crate::pci();
+    Lazy::force(&kernel::LEVEL);
+    wait::initialize();
+
+    let error = init();
+    if error != mpi::ffi::MPI_SUCCESS as ffi::c_int {
+        return error;
+    }
+
+    let world = Communicator(mpi::ffi::RSMPI_COMM_WORLD);
+    liveness::initialize(world.rank(), world.size());
+    error
+}
+
+/// Check that every rank has completed the same collectives, then reset the
+/// segment header for the next job and unmap it, once nothing refers to it.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Finalize() -> ffi::c_int {
+    metrics::flush();
+
+    let world = Communicator(mpi::ffi::RSMPI_COMM_WORLD);
+    let (rank, size) = (world.rank(), world.size());
+
+    let mut map = match crate::pci().map.try_lock() {
+        Ok(map) => map,
+        Err(_) => crate::abort(format!(
+            "rank {} called MPI_Finalize during a collective",
+            rank
+        )),
+    };
+
+    liveness::finalize();
+    liveness::stop();
+
+    if let Some(mapped) = map.as_mut() {
+        // Every rank has returned from its last collective
+        pmpi::PMPI_Barrier(mpi::ffi::RSMPI_COMM_WORLD);
+
+        let pending = Segment::pending(mapped, rank, size);
+        if !pending.is_empty() {
+            crate::abort(format!(
+                "rank {} found ranks {:?} completed different collectives by MPI_Finalize",
+                rank, pending,
+            ));
+        }
+
+        // Every rank has checked the header
+        pmpi::PMPI_Barrier(mpi::ffi::RSMPI_COMM_WORLD);
+
+        if rank == 0 {
+            Segment::reset(mapped);
+        }
+    }
+
+    wait::stop();
+    *map = None;
+    drop(map);
+
+    pmpi::PMPI_Finalize()
+}
+
+/// Abort every rank, if MPI is running.
+pub fn abort() {
+    let mut initialized = 0;
+    unsafe {
+        mpi::ffi::MPI_Initialized(&mut initialized);
+        if initialized != 0 {
+            mpi::ffi::MPI_Abort(mpi::ffi::RSMPI_COMM_WORLD, 1);
+        }
+    }
+}
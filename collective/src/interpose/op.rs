@@ -0,0 +1,98 @@
+//! User-defined reduction operators.
+//!
+//! `MPI_Op_create` and `MPI_Op_free` are interposed to record each user
+//! function and whether it commutes, keyed by the handle the actual MPI
+//! returns. The allreduce algorithms then call the function directly over
+//! shared memory, as `inout = in op inout`.
+
+use std::collections::HashMap;
+use std::ffi;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::interpose::pmpi;
+
+/// Registered operators, keyed by handle (an integer for MPICH, an address for
+/// Open MPI).
+static USER: Lazy<Mutex<HashMap<usize, User>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Operators created by this process so far.
+static CREATED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone)]
+pub struct User {
+    function: mpi::ffi::MPI_User_function,
+    commute: bool,
+    index: u64,
+}
+
+impl User {
+    /// Number of operators created before this one, which agrees across
+    /// ranks that create operators in the same order, unlike handles.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Whether `a op b == b op a`, so contributions may be reduced in
+    /// arrival order.
+    pub fn commute(&self) -> bool {
+        self.commute
+    }
+
+    /// Element-wise `inout = input op inout`.
+    pub fn apply<T>(&self, datatype: mpi::ffi::MPI_Datatype, input: &[T], inout: &mut [T]) {
+        assert_eq!(input.len(), inout.len());
+
+        let function = self.function.expect("Null user function");
+
+        // The length is an `int`, even for large-count collectives
+        let chunk = ffi::c_int::MAX as usize;
+        for (input, inout) in input.chunks(chunk).zip(inout.chunks_mut(chunk)) {
+            let mut len = inout.len() as ffi::c_int;
+            let mut datatype = datatype;
+            unsafe {
+                function(
+                    input.as_ptr() as *mut ffi::c_void,
+                    inout.as_mut_ptr().cast(),
+                    &mut len,
+                    &mut datatype,
+                );
+            }
+        }
+    }
+}
+
+/// User operator registered under `op`, or `None` for predefined operators.
+pub fn lookup(op: mpi::ffi::MPI_Op) -> Option<User> {
+    USER.lock().unwrap().get(&(op as usize)).copied()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Op_create(
+    function: mpi::ffi::MPI_User_function,
+    commute: ffi::c_int,
+    op: *mut mpi::ffi::MPI_Op,
+) -> ffi::c_int {
+    let error = pmpi::PMPI_Op_create(function, commute, op);
+    if error == mpi::ffi::MPI_SUCCESS as ffi::c_int {
+        USER.lock().unwrap().insert(
+            *op as usize,
+            User {
+                function,
+                commute: commute != 0,
+                index: CREATED.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+    }
+    error
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Op_free(op: *mut mpi::ffi::MPI_Op) -> ffi::c_int {
+    // The handle is reset to `MPI_OP_NULL` once freed
+    USER.lock().unwrap().remove(&(*op as usize));
+    pmpi::PMPI_Op_free(op)
+}
@@ -0,0 +1,87 @@
+//! Profiling interface entry points, which every interposed `MPI_*` function
+//! forwards to, so the library works both preloaded and linked ahead of MPI.
+//!
+//! The large-count variants only exist from MPI 4, so they are resolved at
+//! runtime instead of linked against.
+
+use std::ffi;
+use std::mem;
+
+use once_cell::sync::Lazy;
+
+extern "C" {
+    pub fn PMPI_Init(argc: *mut ffi::c_int, argv: *mut *mut *mut ffi::c_char) -> ffi::c_int;
+
+    pub fn PMPI_Init_thread(
+        argc: *mut ffi::c_int,
+        argv: *mut *mut *mut ffi::c_char,
+        required: ffi::c_int,
+        provided: *mut ffi::c_int,
+    ) -> ffi::c_int;
+
+    pub fn PMPI_Finalize() -> ffi::c_int;
+
+    pub fn PMPI_Barrier(comm: mpi::ffi::MPI_Comm) -> ffi::c_int;
+
+    pub fn PMPI_Allreduce(
+        buffer_send: *const ffi::c_void,
+        buffer_receive: *mut ffi::c_void,
+        count: ffi::c_int,
+        datatype: mpi::ffi::MPI_Datatype,
+        op: mpi::ffi::MPI_Op,
+        comm: mpi::ffi::MPI_Comm,
+    ) -> ffi::c_int;
+
+    pub fn PMPI_Bcast(
+        buffer: *mut ffi::c_void,
+        count: ffi::c_int,
+        datatype: mpi::ffi::MPI_Datatype,
+        root: ffi::c_int,
+        comm: mpi::ffi::MPI_Comm,
+    ) -> ffi::c_int;
+
+    pub fn PMPI_Op_create(
+        function: mpi::ffi::MPI_User_function,
+        commute: ffi::c_int,
+        op: *mut mpi::ffi::MPI_Op,
+    ) -> ffi::c_int;
+
+    pub fn PMPI_Op_free(op: *mut mpi::ffi::MPI_Op) -> ffi::c_int;
+
+    pub fn PMPI_Type_free(datatype: *mut mpi::ffi::MPI_Datatype) -> ffi::c_int;
+}
+
+pub static PMPI_Allreduce_c: Lazy<
+    Option<
+        unsafe extern "C" fn(
+            *const ffi::c_void,
+            *mut ffi::c_void,
+            mpi::ffi::MPI_Count,
+            mpi::ffi::MPI_Datatype,
+            mpi::ffi::MPI_Op,
+            mpi::ffi::MPI_Comm,
+        ) -> ffi::c_int,
+    >,
+> = Lazy::new(|| unsafe { mem::transmute(resolve(b"PMPI_Allreduce_c\0")) });
+
+pub static PMPI_Bcast_c: Lazy<
+    Option<
+        unsafe extern "C" fn(
+            *mut ffi::c_void,
+            mpi::ffi::MPI_Count,
+            mpi::ffi::MPI_Datatype,
+            ffi::c_int,
+            mpi::ffi::MPI_Comm,
+        ) -> ffi::c_int,
+    >,
+> = Lazy::new(|| unsafe { mem::transmute(resolve(b"PMPI_Bcast_c\0")) });
+
+/// Address of `symbol`, or null if this MPI predates it.
+fn resolve(symbol: &[u8]) -> *mut ffi::c_void {
+    unsafe {
+        libc::dlsym(
+            libc::RTLD_DEFAULT,
+            ffi::CStr::from_bytes_with_nul(symbol).unwrap().as_ptr(),
+        )
+    }
+}
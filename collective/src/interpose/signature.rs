@@ -0,0 +1,86 @@
+//! Signatures of interposed MPI calls, with datatypes and operators encoded
+//! the same way in every process.
+
+use std::ffi;
+
+use crate::interpose::handle;
+use crate::interpose::op;
+use crate::signature::Collective;
+use crate::signature::Signature;
+use crate::signature::UNKNOWN_OP;
+
+pub(crate) fn new(
+    collective: Collective,
+    count: usize,
+    datatype: mpi::ffi::MPI_Datatype,
+    op: Option<mpi::ffi::MPI_Op>,
+    root: Option<ffi::c_int>,
+) -> Signature {
+    Signature::encoded(
+        collective,
+        count,
+        encode_datatype(datatype),
+        op.map_or(0, encode_op),
+        root,
+    )
+}
+
+/// Handles differ between processes (e.g. Open MPI's are addresses), so
+/// predefined datatypes are encoded by index, alongside their size.
+fn encode_datatype(datatype: mpi::ffi::MPI_Datatype) -> u64 {
+    let predefined = unsafe {
+        [
+            mpi::ffi::RSMPI_FLOAT as usize,
+            mpi::ffi::RSMPI_DOUBLE as usize,
+            mpi::ffi::RSMPI_INT8_T as usize,
+            mpi::ffi::RSMPI_INT16_T as usize,
+            mpi::ffi::RSMPI_INT32_T as usize,
+            mpi::ffi::RSMPI_INT64_T as usize,
+            mpi::ffi::RSMPI_UINT8_T as usize,
+            mpi::ffi::RSMPI_UINT16_T as usize,
+            mpi::ffi::RSMPI_UINT32_T as usize,
+            mpi::ffi::RSMPI_UINT64_T as usize,
+            *handle::FLOAT_INT,
+            *handle::DOUBLE_INT,
+            *handle::TWO_INT,
+            *handle::INT,
+            *handle::SIGNED_CHAR,
+        ]
+    };
+
+    let index = predefined
+        .iter()
+        .position(|predefined| *predefined == datatype as usize)
+        .map_or(0, |index| index as u64 + 1);
+
+    let mut size = 0;
+    unsafe {
+        mpi::ffi::MPI_Type_size(datatype, &mut size);
+    }
+
+    (index << 32) | size as u32 as u64
+}
+
+/// Predefined operators are encoded by index, like datatypes, and user
+/// operators by the order they were created in.
+fn encode_op(op: mpi::ffi::MPI_Op) -> u64 {
+    if let Some(user) = op::lookup(op) {
+        return UNKNOWN_OP + 1 + user.index();
+    }
+
+    let predefined = unsafe {
+        [
+            mpi::ffi::RSMPI_SUM as usize,
+            mpi::ffi::RSMPI_MAX as usize,
+            mpi::ffi::RSMPI_MIN as usize,
+            mpi::ffi::RSMPI_PROD as usize,
+            *handle::MAXLOC,
+            *handle::MINLOC,
+        ]
+    };
+
+    predefined
+        .iter()
+        .position(|predefined| *predefined == op as usize)
+        .map_or(UNKNOWN_OP, |index| index as u64 + 1)
+}
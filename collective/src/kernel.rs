@@ -0,0 +1,555 @@
+//! Vectorized reduction kernels, selected at initialization by CPU feature.
+//!
+//! This module is self-contained so it can also be included by
+//! `benches/kernel.rs`, which compares each level against the scalar fallback.
+
+use std::env;
+
+use once_cell::sync::Lazy;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Scalar,
+    Sse2,
+    Avx2,
+    Avx512,
+}
+
+impl Level {
+    /// Highest level supported by this CPU.
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw") {
+                return Level::Avx512;
+            }
+            if is_x86_feature_detected!("avx2") {
+                return Level::Avx2;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return Level::Sse2;
+            }
+        }
+        Level::Scalar
+    }
+}
+
+/// Kernel level used by the reduction, overridable with `COLLECTIVE_KERNEL`.
+pub static LEVEL: Lazy<Level> = Lazy::new(|| {
+    let detected = Level::detect();
+    let level = match env::var("COLLECTIVE_KERNEL").as_deref() {
+        Err(_) => detected,
+        Ok("scalar") => Level::Scalar,
+        Ok("sse2") => Level::Sse2,
+        Ok("avx2") => Level::Avx2,
+        Ok("avx512") => Level::Avx512,
+        Ok(level) => panic!("Unknown kernel level: {}", level),
+    };
+    assert!(
+        level <= detected,
+        "Kernel level {:?} not supported by CPU (detected {:?})",
+        level,
+        detected,
+    );
+    level
+});
+
+#[cfg(target_arch = "x86_64")]
+macro_rules! simd {
+    ($feature:literal, $name:ident, $type:ty, $vector:ty, $load:path, $add:path, $store:path) => {
+        #[target_feature(enable = $feature)]
+        unsafe fn $name(shared: &mut [$type], other: &[$type]) {
+            const LANES: usize = std::mem::size_of::<$vector>() / std::mem::size_of::<$type>();
+
+            let split = shared.len() / LANES * LANES;
+            let (shared_head, shared_tail) = shared.split_at_mut(split);
+            let (other_head, other_tail) = other.split_at(split);
+
+            shared_head
+                .chunks_exact_mut(LANES)
+                .zip(other_head.chunks_exact(LANES))
+                .for_each(|(shared, other)| {
+                    let sum = $add($load(shared.as_ptr().cast()), $load(other.as_ptr().cast()));
+                    $store(shared.as_mut_ptr().cast(), sum);
+                });
+
+            scalar(shared_tail, other_tail);
+        }
+    };
+}
+
+macro_rules! kernel {
+    ($name:ident, $type:ty, |$shared:ident, $other:ident| $scalar:expr,
+     sse2: ($sse2_load:ident, $sse2_add:ident, $sse2_store:ident),
+     avx2: ($avx2_load:ident, $avx2_add:ident, $avx2_store:ident),
+     avx512: ($avx512_load:ident, $avx512_add:ident, $avx512_store:ident) $(,)?) => {
+        pub mod $name {
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::*;
+
+            use super::Level;
+
+            /// Element-wise `shared += other` using the initialized kernel level.
+            pub fn sum(shared: &mut [$type], other: &[$type]) {
+                assert_eq!(shared.len(), other.len());
+                dispatch(*super::LEVEL, shared, other)
+            }
+
+            /// Element-wise `shared += other` using kernel `level`.
+            ///
+            /// Panics if `level` is not supported by this CPU.
+            #[allow(dead_code)]
+            pub fn sum_with(level: Level, shared: &mut [$type], other: &[$type]) {
+                assert_eq!(shared.len(), other.len());
+                assert!(level <= Level::detect());
+                dispatch(level, shared, other)
+            }
+
+            fn dispatch(level: Level, shared: &mut [$type], other: &[$type]) {
+                #[cfg(target_arch = "x86_64")]
+                unsafe {
+                    match level {
+                        Level::Scalar => scalar(shared, other),
+                        Level::Sse2 => sse2(shared, other),
+                        Level::Avx2 => avx2(shared, other),
+                        Level::Avx512 => avx512(shared, other),
+                    }
+                }
+
+                #[cfg(not(target_arch = "x86_64"))]
+                scalar(shared, other)
+            }
+
+            fn scalar(shared: &mut [$type], other: &[$type]) {
+                shared
+                    .iter_mut()
+                    .zip(other)
+                    .for_each(|($shared, $other)| $scalar);
+            }
+
+            #[cfg(target_arch = "x86_64")]
+            simd!(
+                "sse2",
+                sse2,
+                $type,
+                __m128i,
+                $sse2_load,
+                $sse2_add,
+                $sse2_store
+            );
+
+            #[cfg(target_arch = "x86_64")]
+            simd!(
+                "avx2",
+                avx2,
+                $type,
+                __m256i,
+                $avx2_load,
+                $avx2_add,
+                $avx2_store
+            );
+
+            #[cfg(target_arch = "x86_64")]
+            simd!(
+                "avx512f,avx512bw",
+                avx512,
+                $type,
+                __m512i,
+                $avx512_load,
+                $avx512_add,
+                $avx512_store
+            );
+        }
+    };
+}
+
+kernel!(
+    f32,
+    f32,
+    |shared, other| *shared += other,
+    sse2: (_mm_loadu_ps, _mm_add_ps, _mm_storeu_ps),
+    avx2: (_mm256_loadu_ps, _mm256_add_ps, _mm256_storeu_ps),
+    avx512: (_mm512_loadu_ps, _mm512_add_ps, _mm512_storeu_ps),
+);
+
+kernel!(
+    f64,
+    f64,
+    |shared, other| *shared += other,
+    sse2: (_mm_loadu_pd, _mm_add_pd, _mm_storeu_pd),
+    avx2: (_mm256_loadu_pd, _mm256_add_pd, _mm256_storeu_pd),
+    avx512: (_mm512_loadu_pd, _mm512_add_pd, _mm512_storeu_pd),
+);
+
+kernel!(
+    i8,
+    i8,
+    |shared, other| *shared = shared.wrapping_add(*other),
+    sse2: (_mm_loadu_si128, _mm_add_epi8, _mm_storeu_si128),
+    avx2: (_mm256_loadu_si256, _mm256_add_epi8, _mm256_storeu_si256),
+    avx512: (_mm512_loadu_si512, _mm512_add_epi8, _mm512_storeu_si512),
+);
+
+kernel!(
+    i32,
+    i32,
+    |shared, other| *shared = shared.wrapping_add(*other),
+    sse2: (_mm_loadu_si128, _mm_add_epi32, _mm_storeu_si128),
+    avx2: (_mm256_loadu_si256, _mm256_add_epi32, _mm256_storeu_si256),
+    avx512: (_mm512_loadu_si512, _mm512_add_epi32, _mm512_storeu_si512),
+);
+
+/// Compensated floating point summation, which keeps the rounding error of
+/// each addition in a separate `compensation` buffer, to be added to the
+/// result once all contributions are summed.
+///
+/// Uses the branchless TwoSum error term, which every level computes with the
+/// same additions and subtractions, so all levels give identical results.
+pub mod compensated {
+    #[cfg(target_arch = "x86_64")]
+    macro_rules! compensated_simd {
+        ($feature:literal, $name:ident, $type:ty, $vector:ty,
+         $load:path, $add:path, $sub:path, $store:path) => {
+            #[target_feature(enable = $feature)]
+            unsafe fn $name(shared: &mut [$type], compensation: &mut [$type], other: &[$type]) {
+                const LANES: usize = std::mem::size_of::<$vector>() / std::mem::size_of::<$type>();
+
+                let split = shared.len() / LANES * LANES;
+                let (shared_head, shared_tail) = shared.split_at_mut(split);
+                let (compensation_head, compensation_tail) = compensation.split_at_mut(split);
+                let (other_head, other_tail) = other.split_at(split);
+
+                shared_head
+                    .chunks_exact_mut(LANES)
+                    .zip(compensation_head.chunks_exact_mut(LANES))
+                    .zip(other_head.chunks_exact(LANES))
+                    .for_each(|((shared, compensation), other)| {
+                        let shared_value = $load(shared.as_ptr().cast());
+                        let other_value = $load(other.as_ptr().cast());
+
+                        let sum = $add(shared_value, other_value);
+                        let other_rounded = $sub(sum, shared_value);
+                        let shared_rounded = $sub(sum, other_rounded);
+                        let error = $add(
+                            $sub(shared_value, shared_rounded),
+                            $sub(other_value, other_rounded),
+                        );
+
+                        let accumulated = $add($load(compensation.as_ptr().cast()), error);
+                        $store(compensation.as_mut_ptr().cast(), accumulated);
+                        $store(shared.as_mut_ptr().cast(), sum);
+                    });
+
+                scalar(shared_tail, compensation_tail, other_tail);
+            }
+        };
+    }
+
+    macro_rules! compensated {
+        ($name:ident, $type:ty,
+         sse2: ($sse2_load:ident, $sse2_add:ident, $sse2_sub:ident, $sse2_store:ident),
+         avx2: ($avx2_load:ident, $avx2_add:ident, $avx2_sub:ident, $avx2_store:ident),
+         avx512: ($avx512_load:ident, $avx512_add:ident, $avx512_sub:ident, $avx512_store:ident) $(,)?) => {
+            pub mod $name {
+                #[cfg(target_arch = "x86_64")]
+                use std::arch::x86_64::*;
+
+                use super::super::Level;
+
+                /// Element-wise `shared += other`, accumulating the rounding
+                /// error into `compensation`, using the initialized kernel level.
+                pub fn sum(shared: &mut [$type], compensation: &mut [$type], other: &[$type]) {
+                    assert_eq!(shared.len(), other.len());
+                    assert_eq!(shared.len(), compensation.len());
+                    dispatch(*super::super::LEVEL, shared, compensation, other)
+                }
+
+                /// Like `sum`, using kernel `level`.
+                ///
+                /// Panics if `level` is not supported by this CPU.
+                #[allow(dead_code)]
+                pub fn sum_with(
+                    level: Level,
+                    shared: &mut [$type],
+                    compensation: &mut [$type],
+                    other: &[$type],
+                ) {
+                    assert_eq!(shared.len(), other.len());
+                    assert_eq!(shared.len(), compensation.len());
+                    assert!(level <= Level::detect());
+                    dispatch(level, shared, compensation, other)
+                }
+
+                fn dispatch(
+                    level: Level,
+                    shared: &mut [$type],
+                    compensation: &mut [$type],
+                    other: &[$type],
+                ) {
+                    #[cfg(target_arch = "x86_64")]
+                    unsafe {
+                        match level {
+                            Level::Scalar => scalar(shared, compensation, other),
+                            Level::Sse2 => sse2(shared, compensation, other),
+                            Level::Avx2 => avx2(shared, compensation, other),
+                            Level::Avx512 => avx512(shared, compensation, other),
+                        }
+                    }
+
+                    #[cfg(not(target_arch = "x86_64"))]
+                    scalar(shared, compensation, other)
+                }
+
+                fn scalar(shared: &mut [$type], compensation: &mut [$type], other: &[$type]) {
+                    shared
+                        .iter_mut()
+                        .zip(compensation.iter_mut())
+                        .zip(other)
+                        .for_each(|((shared, compensation), other)| {
+                            let sum = *shared + other;
+                            let other_rounded = sum - *shared;
+                            let shared_rounded = sum - other_rounded;
+                            *compensation += (*shared - shared_rounded) + (other - other_rounded);
+                            *shared = sum;
+                        });
+                }
+
+                #[cfg(target_arch = "x86_64")]
+                compensated_simd!(
+                    "sse2",
+                    sse2,
+                    $type,
+                    __m128i,
+                    $sse2_load,
+                    $sse2_add,
+                    $sse2_sub,
+                    $sse2_store
+                );
+
+                #[cfg(target_arch = "x86_64")]
+                compensated_simd!(
+                    "avx2",
+                    avx2,
+                    $type,
+                    __m256i,
+                    $avx2_load,
+                    $avx2_add,
+                    $avx2_sub,
+                    $avx2_store
+                );
+
+                #[cfg(target_arch = "x86_64")]
+                compensated_simd!(
+                    "avx512f",
+                    avx512,
+                    $type,
+                    __m512i,
+                    $avx512_load,
+                    $avx512_add,
+                    $avx512_sub,
+                    $avx512_store
+                );
+            }
+        };
+    }
+
+    compensated!(
+        f32,
+        f32,
+        sse2: (_mm_loadu_ps, _mm_add_ps, _mm_sub_ps, _mm_storeu_ps),
+        avx2: (_mm256_loadu_ps, _mm256_add_ps, _mm256_sub_ps, _mm256_storeu_ps),
+        avx512: (_mm512_loadu_ps, _mm512_add_ps, _mm512_sub_ps, _mm512_storeu_ps),
+    );
+
+    compensated!(
+        f64,
+        f64,
+        sse2: (_mm_loadu_pd, _mm_add_pd, _mm_sub_pd, _mm_storeu_pd),
+        avx2: (_mm256_loadu_pd, _mm256_add_pd, _mm256_sub_pd, _mm256_storeu_pd),
+        avx512: (_mm512_loadu_pd, _mm512_add_pd, _mm512_sub_pd, _mm512_storeu_pd),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lengths on either side of every vector width.
+    const LENGTHS: [usize; 12] = [0, 1, 3, 7, 15, 16, 17, 31, 63, 64, 65, 1001];
+
+    /// Deterministic xorshift, so failures reproduce.
+    fn random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn levels() -> impl Iterator<Item = Level> {
+        [Level::Sse2, Level::Avx2, Level::Avx512]
+            .into_iter()
+            .filter(|level| *level <= Level::detect())
+    }
+
+    /// Every level sums `$name` exactly as the scalar kernel does, from
+    /// offsets unaligning both slices, with elements made of random bits by
+    /// `$value`.
+    macro_rules! matches_scalar {
+        ($test:ident, $name:ident, $type:ty, |$bits:ident| $value:expr) => {
+            #[test]
+            fn $test() {
+                let mut state = 0x9E37_79B9_7F4A_7C15;
+                let mut values = |len: usize| {
+                    (0..len)
+                        .map(|_| {
+                            let $bits = random(&mut state);
+                            $value
+                        })
+                        .collect::<Vec<$type>>()
+                };
+
+                for len in LENGTHS {
+                    for offset in 0..4 {
+                        let shared = values(len + 3);
+                        let other = values(len + 3);
+                        let other = &other[3 - offset..][..len];
+
+                        let mut expected = shared.clone();
+                        $name::sum_with(Level::Scalar, &mut expected[offset..][..len], other);
+
+                        for level in levels() {
+                            let mut actual = shared.clone();
+                            $name::sum_with(level, &mut actual[offset..][..len], other);
+                            assert_eq!(actual, expected, "{:?}, {} at {}", level, len, offset);
+                        }
+                    }
+                }
+            }
+        };
+    }
+
+    // Finite values of either sign, from subnormal up to 2
+    matches_scalar!(f32_matches_scalar, f32, f32, |bits| f32::from_bits(
+        bits as u32 & !(1 << 30)
+    ));
+    matches_scalar!(f64_matches_scalar, f64, f64, |bits| f64::from_bits(
+        bits & !(1 << 62)
+    ));
+    // Overflowing, which wraps at every level
+    matches_scalar!(i8_matches_scalar, i8, i8, |bits| bits as i8);
+    matches_scalar!(i32_matches_scalar, i32, i32, |bits| bits as i32);
+
+    /// Every level sums `compensated::$name` exactly as the scalar kernel
+    /// does, into random sums and compensations.
+    macro_rules! compensated_matches_scalar {
+        ($test:ident, $name:ident, $type:ty, |$bits:ident| $value:expr) => {
+            #[test]
+            fn $test() {
+                let mut state = 0x9E37_79B9_7F4A_7C15;
+                let mut values = |len: usize| {
+                    (0..len)
+                        .map(|_| {
+                            let $bits = random(&mut state);
+                            $value
+                        })
+                        .collect::<Vec<$type>>()
+                };
+
+                for len in LENGTHS {
+                    for offset in 0..4 {
+                        let shared = values(len + 3);
+                        let compensation = values(len + 3);
+                        let other = values(len + 3);
+                        let other = &other[3 - offset..][..len];
+
+                        let mut expected = (shared.clone(), compensation.clone());
+                        compensated::$name::sum_with(
+                            Level::Scalar,
+                            &mut expected.0[offset..][..len],
+                            &mut expected.1[offset..][..len],
+                            other,
+                        );
+
+                        for level in levels() {
+                            let mut actual = (shared.clone(), compensation.clone());
+                            compensated::$name::sum_with(
+                                level,
+                                &mut actual.0[offset..][..len],
+                                &mut actual.1[offset..][..len],
+                                other,
+                            );
+                            assert_eq!(actual, expected, "{:?}, {} at {}", level, len, offset);
+                        }
+                    }
+                }
+            }
+        };
+    }
+
+    compensated_matches_scalar!(compensated_f32_matches_scalar, f32, f32, |bits| {
+        f32::from_bits(bits as u32 & !(1 << 30))
+    });
+    compensated_matches_scalar!(compensated_f64_matches_scalar, f64, f64, |bits| {
+        f64::from_bits(bits & !(1 << 62))
+    });
+
+    #[test]
+    fn compensated_beats_naive() {
+        /// Sums of `contributions`, as allreduce adds them, with and without
+        /// compensation, over a length covering vectors and the scalar tail.
+        macro_rules! sums {
+            ($name:ident, $level:expr, $contributions:expr) => {{
+                const LEN: usize = 67;
+                let (first, rest) = $contributions.split_first().unwrap();
+
+                let mut naive = [*first; LEN];
+                let mut compensated = [*first; LEN];
+                let mut compensation = [0.0; LEN];
+                for contribution in rest {
+                    $name::sum_with($level, &mut naive, &[*contribution; LEN]);
+                    compensated::$name::sum_with(
+                        $level,
+                        &mut compensated,
+                        &mut compensation,
+                        &[*contribution; LEN],
+                    );
+                }
+                $name::sum_with($level, &mut compensated, &compensation);
+                (naive, compensated)
+            }};
+        }
+
+        for level in std::iter::once(Level::Scalar).chain(levels()) {
+            // Large contributions cancelling out, leaving the small ones that
+            // naive summation rounds away
+            let (naive, compensated) = sums!(f64, level, [1.0, 1e100, 1.0, -1e100]);
+            assert!(naive.iter().all(|sum| *sum == 0.0));
+            assert!(compensated.iter().all(|sum| *sum == 2.0));
+
+            let (naive, compensated) = sums!(f32, level, [1.0, 1e20, 1.0, -1e20]);
+            assert!(naive.iter().all(|sum| *sum == 0.0));
+            assert!(compensated.iter().all(|sum| *sum == 2.0));
+
+            // Many contributions each below half an ulp of the sum
+            let mut contributions = vec![1e-16; 1001];
+            contributions[0] = 1.0;
+            let (naive, compensated) = sums!(f64, level, contributions);
+            let exact = 1.0 + 1e-13;
+            assert!(naive.iter().all(|sum| *sum == 1.0));
+            assert!(compensated
+                .iter()
+                .all(|sum| (sum - exact).abs() <= f64::EPSILON));
+        }
+    }
+
+    #[test]
+    fn integers_wrap() {
+        let mut shared = [i8::MAX, i8::MIN];
+        i8::sum(&mut shared, &[1, -1]);
+        assert_eq!(shared, [i8::MIN, i8::MAX]);
+
+        let mut shared = [i32::MAX, i32::MIN];
+        i32::sum(&mut shared, &[1, -1]);
+        assert_eq!(shared, [i32::MIN, i32::MAX]);
+    }
+}
@@ -3,70 +3,125 @@
 #![allow(non_upper_case_globals)]
 
 mod allreduce;
+mod bakery;
 mod barrier;
 mod broadcast;
+mod capi;
+mod checksum;
+mod coherence;
+mod copy;
 mod datatype;
+mod dissemination;
+mod group;
+mod guard;
+#[cfg(feature = "mpi")]
+mod interpose;
+mod kernel;
+mod liveness;
+mod local;
+mod lock;
+mod mcs;
 mod metrics;
 mod mutex;
+mod notifier;
+mod quantize;
+mod segment;
+mod signature;
+mod ticket;
+mod tournament;
+mod tree;
+mod wait;
 
 use std::env;
-use std::ffi;
 use std::fs;
-use std::mem;
+use std::ops::Deref;
+use std::ops::DerefMut;
 use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::anyhow;
 use anyhow::Context as _;
 use memmap2::MmapMut;
-use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+
+pub use group::Config;
+pub use group::Element;
+pub use group::Group;
 
 const CACHE_LINE_SIZE: usize = 64;
 const PAGE_SIZE: usize = 4096;
 
-static PCI_SIZE: Lazy<usize> = Lazy::new(|| initialize_size().unwrap());
-static PCI_FILE: Lazy<fs::File> = Lazy::new(|| initialize_file().unwrap());
-static PCI_MAP: Lazy<std::sync::Mutex<MmapMut>> =
-    Lazy::new(|| initialize_map().map(std::sync::Mutex::new).unwrap());
+static PCI: OnceCell<Pci> = OnceCell::new();
+
+/// Shared memory file, and its mapping until `MPI_Finalize`.
+struct Pci {
+    file: fs::File,
+    size: usize,
+    map: std::sync::Mutex<Option<MmapMut>>,
+}
+
+impl Pci {
+    fn open(path: &Path, size: usize) -> anyhow::Result<Self> {
+        let file = initialize_file(path)?;
+        let map = initialize_map(&file, size)?;
+        Ok(Pci {
+            file,
+            size,
+            map: std::sync::Mutex::new(Some(map)),
+        })
+    }
+
+    /// Bytes of the slot each collective call gets, known without locking.
+    fn slot_size(&self) -> usize {
+        segment::slot_size(self.size)
+    }
+
+    /// Lock the mapping for one collective call.
+    fn lock(&self) -> Map<'_> {
+        Map(self.map.lock().unwrap())
+    }
+}
 
-struct Communicator(mpi::ffi::MPI_Comm);
+struct Map<'pci>(std::sync::MutexGuard<'pci, Option<MmapMut>>);
 
-unsafe impl mpi::traits::AsRaw for Communicator {
-    type Raw = mpi::ffi::MPI_Comm;
-    fn as_raw(&self) -> Self::Raw {
+impl Deref for Map<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
         self.0
+            .as_deref()
+            .expect("Shared memory unmapped by MPI_Finalize")
     }
 }
 
-impl mpi::traits::Communicator for Communicator {}
-
-#[no_mangle]
-pub unsafe extern "C" fn MPI_Init_thread(
-    argc: *const ffi::c_int,
-    argv: *const *const *const ffi::c_char,
-    required: ffi::c_int,
-    provided: *const ffi::c_int,
-) {
-    Lazy::force(&PCI_FILE);
-    Lazy::force(&PCI_MAP);
-
-    // Forward to actual `MPI_Init_thread`
-    static _MPI_Init_thread: Lazy<
-        unsafe extern "C" fn(
-            *const ffi::c_int,
-            *const *const *const ffi::c_char,
-            ffi::c_int,
-            *const ffi::c_int,
-        ),
-    > = Lazy::new(|| unsafe {
-        mem::transmute(libc::dlsym(
-            libc::RTLD_NEXT,
-            ffi::CStr::from_bytes_with_nul(b"MPI_Init_thread\0")
-                .unwrap()
-                .as_ptr(),
-        ))
-    });
-
-    _MPI_Init_thread(argc, argv, required, provided)
+impl DerefMut for Map<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+            .as_deref_mut()
+            .expect("Shared memory unmapped by MPI_Finalize")
+    }
+}
+
+/// Shared memory opened by `Group::new`, or else from `COLLECTIVE_PCI_PATH`
+/// and `COLLECTIVE_PCI_SIZE` on first use.
+fn pci() -> &'static Pci {
+    PCI.get_or_init(|| {
+        initialize_path()
+            .and_then(|path| Pci::open(&path, initialize_size()?))
+            .unwrap()
+    })
+}
+
+/// Report an unrecoverable error and abort the whole job.
+fn abort(message: String) -> ! {
+    eprintln!("collective: {}", message);
+
+    // Groups opened without MPI just abort this process
+    #[cfg(feature = "mpi")]
+    interpose::abort();
+
+    // `MPI_Abort` should not return
+    std::process::abort()
 }
 
 fn initialize_size() -> anyhow::Result<usize> {
@@ -76,11 +131,13 @@ fn initialize_size() -> anyhow::Result<usize> {
         .context("Failed to parse COLLECTIVE_PCI_SIZE as usize")
 }
 
-fn initialize_file() -> anyhow::Result<fs::File> {
-    let path = env::var("COLLECTIVE_PCI_PATH")
-        .context("Missing COLLECTIVE_PCI_PATH environment variable")?;
-    let path = path.trim();
+fn initialize_path() -> anyhow::Result<PathBuf> {
+    env::var("COLLECTIVE_PCI_PATH")
+        .context("Missing COLLECTIVE_PCI_PATH environment variable")
+        .map(|path| PathBuf::from(path.trim()))
+}
 
+fn initialize_file(path: &Path) -> anyhow::Result<fs::File> {
     let o_direct = match env::var("COLLECTIVE_O_DIRECT") {
         Ok(_) => libc::O_DIRECT,
         Err(_) => 0,
@@ -96,14 +153,14 @@ fn initialize_file() -> anyhow::Result<fs::File> {
         .write(true)
         .custom_flags(o_direct | o_sync)
         .open(path)
-        .with_context(|| anyhow!("Failed to read {}", path))
+        .with_context(|| anyhow!("Failed to read {}", path.display()))
 }
 
-fn initialize_map() -> anyhow::Result<MmapMut> {
+fn initialize_map(file: &fs::File, size: usize) -> anyhow::Result<MmapMut> {
     unsafe {
         memmap2::MmapOptions::new()
-            .len(*PCI_SIZE)
-            .map_mut(&*PCI_FILE)
+            .len(size)
+            .map_mut(file)
             .context("Failed to mmap PCI file")
     }
 }
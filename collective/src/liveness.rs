@@ -0,0 +1,262 @@
+//! Per-rank heartbeat and state words, to tell slow peers from dead ones.
+//!
+//! If `COLLECTIVE_HEARTBEAT_MS` is set, each rank runs a thread that bumps its
+//! heartbeat at that interval, and publishes whether it is idle, inside a
+//! collective (by call sequence number), or finalized. At most once per
+//! interval, a stalled wait checks every peer, and aborts if one's heartbeat
+//! has not advanced for `COLLECTIVE_HEARTBEAT_TIMEOUT_MS` (default 1000)
+//! without it finalizing, or if a peer it is waiting on has finalized.
+//!
+//! | Rank 0 Heartbeat, State |
+//! | Rank 1 Heartbeat, State |
+//! | ...                     |
+
+use std::env;
+use std::ffi;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+
+use crate::coherence;
+use crate::segment;
+
+static INTERVAL: Lazy<Option<Duration>> = Lazy::new(|| match env::var("COLLECTIVE_HEARTBEAT_MS") {
+    Err(_) => None,
+    Ok(interval) => interval
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .map(Some)
+        .expect("Failed to parse COLLECTIVE_HEARTBEAT_MS as u64"),
+});
+
+static TIMEOUT: Lazy<Duration> = Lazy::new(|| match env::var("COLLECTIVE_HEARTBEAT_TIMEOUT_MS") {
+    Err(_) => Duration::from_secs(1),
+    Ok(timeout) => timeout
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .expect("Failed to parse COLLECTIVE_HEARTBEAT_TIMEOUT_MS as u64"),
+});
+
+static LIVENESS: OnceCell<Liveness> = OnceCell::new();
+
+static HEARTBEAT: Mutex<Option<thread::JoinHandle<()>>> = Mutex::new(None);
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Uninitialized,
+    Idle,
+    Collective(u64),
+    Finalized,
+}
+
+impl State {
+    fn encode(self) -> u64 {
+        match self {
+            State::Uninitialized => 0,
+            State::Idle => 1,
+            State::Finalized => 2,
+            State::Collective(sequence) => sequence + 3,
+        }
+    }
+
+    fn decode(value: u64) -> Self {
+        match value {
+            0 => State::Uninitialized,
+            1 => State::Idle,
+            2 => State::Finalized,
+            sequence => State::Collective(sequence - 3),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Uninitialized => write!(f, "uninitialized"),
+            State::Idle => write!(f, "idle"),
+            State::Collective(sequence) => write!(f, "in-collective-{}", sequence),
+            State::Finalized => write!(f, "finalized"),
+        }
+    }
+}
+
+struct Liveness {
+    /// Start of the header, or null once `stop` has forgotten it, before it
+    /// is reset and unmapped.
+    lines: AtomicPtr<AtomicU64>,
+    rank: ffi::c_int,
+    total: ffi::c_int,
+
+    /// Last heartbeat observed from each peer, and when it last advanced.
+    observed: Mutex<Vec<(u64, Instant)>>,
+}
+
+impl Liveness {
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    /// Word `word` of `rank`'s line, unless the header is forgotten.
+    fn word(&self, rank: ffi::c_int, word: usize) -> Option<&AtomicU64> {
+        let lines = self.lines.load(Ordering::Acquire);
+        (!lines.is_null()).then(|| unsafe { &*lines.add(rank as usize * Self::WORDS + word) })
+    }
+
+    fn heartbeat(&self, rank: ffi::c_int) -> Option<&AtomicU64> {
+        self.word(rank, 0)
+    }
+
+    fn state(&self, rank: ffi::c_int) -> Option<&AtomicU64> {
+        self.word(rank, 1)
+    }
+
+    /// State of `rank`, and how long since its heartbeat last advanced,
+    /// unless the header is forgotten.
+    fn observe(&self, rank: ffi::c_int) -> Option<(State, Duration)> {
+        let heartbeat = coherence::load(self.heartbeat(rank)?, Ordering::Acquire);
+        let state = State::decode(coherence::load(self.state(rank)?, Ordering::Acquire));
+
+        let mut observed = self.observed.lock().unwrap();
+        let (last, since) = &mut observed[rank as usize];
+        if heartbeat != *last {
+            *last = heartbeat;
+            *since = Instant::now();
+        }
+
+        Some((state, since.elapsed()))
+    }
+}
+
+/// Start publishing heartbeats, if enabled. Must be called once ranks are
+/// known, and before any collective locks the shared memory map.
+pub fn initialize(rank: ffi::c_int, total: ffi::c_int) {
+    let Some(interval) = *INTERVAL else {
+        return;
+    };
+    Lazy::force(&TIMEOUT);
+
+    assert!(
+        total as usize * crate::CACHE_LINE_SIZE <= segment::LIVENESS_SIZE,
+        "Too many ranks for liveness header",
+    );
+
+    let lines = crate::pci().lock()[segment::LIVENESS_OFFSET..]
+        .as_mut_ptr()
+        .cast();
+
+    let now = Instant::now();
+    let liveness = LIVENESS.get_or_init(|| Liveness {
+        lines: AtomicPtr::new(lines),
+        rank,
+        total,
+        observed: Mutex::new(vec![(0, now); total as usize]),
+    });
+
+    set(State::Idle);
+
+    let heartbeat = thread::Builder::new()
+        .name(String::from("collective-heartbeat"))
+        .spawn(move || {
+            while let Some(heartbeat) = liveness.heartbeat(rank) {
+                coherence::store(
+                    heartbeat,
+                    heartbeat.load(Ordering::Relaxed) + 1,
+                    Ordering::Release,
+                );
+                thread::park_timeout(interval);
+            }
+        })
+        .expect("Failed to spawn heartbeat thread");
+    *HEARTBEAT.lock().unwrap() = Some(heartbeat);
+
+    unsafe {
+        libc::atexit(exit);
+    }
+}
+
+/// Whether waits should check peers, and how often at most.
+pub fn interval() -> Option<Duration> {
+    LIVENESS
+        .get()
+        .filter(|liveness| !liveness.lines.load(Ordering::Acquire).is_null())
+        .and(*INTERVAL)
+}
+
+/// Publish that this rank is executing call `sequence`.
+pub fn enter(sequence: u64) {
+    set(State::Collective(sequence));
+}
+
+/// Publish that this rank is between collectives.
+pub fn leave() {
+    set(State::Idle);
+}
+
+/// Publish that this rank will not call any more collectives.
+pub fn finalize() {
+    set(State::Finalized);
+}
+
+/// Forget the header and stop the heartbeat thread, before the header is
+/// reset and unmapped, so that later state changes (e.g. on exit) and checks
+/// leave it alone.
+pub fn stop() {
+    if let Some(liveness) = LIVENESS.get() {
+        liveness.lines.store(ptr::null_mut(), Ordering::Release);
+    }
+    if let Some(heartbeat) = HEARTBEAT.lock().unwrap().take() {
+        heartbeat.thread().unpark();
+        heartbeat.join().expect("Heartbeat thread panicked");
+    }
+}
+
+/// Find a peer that is gone, with a description of why. Finalized peers
+/// only count if they are `missing` from the current wait.
+pub fn check(missing: Option<&[ffi::c_int]>) -> Option<(ffi::c_int, String)> {
+    let liveness = LIVENESS.get()?;
+
+    (0..liveness.total)
+        .filter(|rank| *rank != liveness.rank)
+        .find_map(|rank| match liveness.observe(rank)? {
+            (State::Finalized, _) => missing
+                .is_some_and(|missing| missing.contains(&rank))
+                .then(|| (rank, String::from("finalized"))),
+            (state, stale) if stale >= *TIMEOUT => Some((
+                rank,
+                format!("no heartbeat for {:?}, last {}", stale, state),
+            )),
+            _ => None,
+        })
+}
+
+/// Describe `rank` as seen by this rank, e.g. `"alive, idle"`.
+pub fn describe(rank: ffi::c_int) -> Option<String> {
+    let (state, stale) = LIVENESS.get()?.observe(rank)?;
+    let liveness = match state == State::Finalized || stale >= *TIMEOUT {
+        true => "gone",
+        false => "alive",
+    };
+
+    Some(format!("{}, {}", liveness, state))
+}
+
+fn set(state: State) {
+    if let Some(word) = LIVENESS
+        .get()
+        .and_then(|liveness| liveness.state(liveness.rank))
+    {
+        coherence::store(word, state.encode(), Ordering::Release);
+    }
+}
+
+extern "C" fn exit() {
+    finalize();
+}
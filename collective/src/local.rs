@@ -0,0 +1,64 @@
+//! A rank's own buffers in a collective, copied into and out of the slot.
+//!
+//! Collectives only see contiguous buffers here. The MPI interposer adds
+//! derived datatypes, gathered into and scattered out of the slot directly.
+
+use crate::checksum;
+use crate::copy;
+
+pub(crate) trait Local<T> {
+    /// Number of `T`s sent and received.
+    fn len(&self) -> usize;
+
+    /// Copy this rank's data into `shared`, and checksum it.
+    unsafe fn write(&self, shared: &mut [T], checksums: Option<&mut [u32]>);
+
+    /// Copy `shared` out to this rank, returning whether its checksums
+    /// matched, or `true` if there are none. `name` describes `shared` in
+    /// mismatch reports.
+    unsafe fn read(&mut self, name: &str, shared: &[T], checksums: Option<&[u32]>) -> bool;
+}
+
+/// Sends `.0` and receives into `.1`, of the same length.
+pub(crate) struct Contiguous<'a, T>(pub &'a [T], pub &'a mut [T]);
+
+impl<T: Copy> Local<T> for Contiguous<'_, T> {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    unsafe fn write(&self, shared: &mut [T], checksums: Option<&mut [u32]>) {
+        write(self.0, shared, checksums)
+    }
+
+    unsafe fn read(&mut self, name: &str, shared: &[T], checksums: Option<&[u32]>) -> bool {
+        read(self.1, name, shared, checksums)
+    }
+}
+
+/// Sends and receives in place, as in a broadcast.
+impl<T: Copy> Local<T> for &mut [T] {
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    unsafe fn write(&self, shared: &mut [T], checksums: Option<&mut [u32]>) {
+        write(self, shared, checksums)
+    }
+
+    unsafe fn read(&mut self, name: &str, shared: &[T], checksums: Option<&[u32]>) -> bool {
+        read(self, name, shared, checksums)
+    }
+}
+
+fn write<T: Copy>(local: &[T], shared: &mut [T], checksums: Option<&mut [u32]>) {
+    copy::write(shared, local);
+    if let Some(checksums) = checksums {
+        checksum::compute(local, checksums);
+    }
+}
+
+fn read<T: Copy>(local: &mut [T], name: &str, shared: &[T], checksums: Option<&[u32]>) -> bool {
+    copy::read(local, shared);
+    checksums.is_none_or(|checksums| checksum::verify(name, local, checksums, 0))
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::ffi;
+use std::hint;
+
+use once_cell::sync::Lazy;
+
+/// Lock living in the shared segment.
+///
+/// Implementations are selected by `COLLECTIVE_LOCK_ALGORITHM`, and
+/// algorithms are generic over this trait so each is monomorphized per lock.
+pub trait Lock<'pci>: Sized {
+    /// Bytes of shared memory required by a single lock for `total` ranks.
+    fn size(total: ffi::c_int) -> usize;
+
+    /// Requires first `Self::size(total)` bytes to be zero-initialized.
+    unsafe fn new(address: *const u8, rank: ffi::c_int, total: ffi::c_int) -> Self;
+
+    fn lock(&self);
+
+    fn unlock(&self);
+}
+
+#[derive(Copy, Clone)]
+pub enum Algorithm {
+    Ttas,
+    Bakery,
+    Ticket,
+    Mcs,
+}
+
+pub static ALGORITHM: Lazy<Algorithm> =
+    Lazy::new(|| match env::var("COLLECTIVE_LOCK_ALGORITHM").as_deref() {
+        Ok("ttas") | Err(_) => Algorithm::Ttas,
+        Ok("bakery") => Algorithm::Bakery,
+        Ok("ticket") => Algorithm::Ticket,
+        Ok("mcs") => Algorithm::Mcs,
+        Ok(algorithm) => panic!("Unknown lock algorithm: {}", algorithm),
+    });
+
+static BACKOFF: Lazy<bool> = Lazy::new(|| env::var("COLLECTIVE_LOCK_BACKOFF").is_ok());
+
+/// Exponential backoff between polls of a contended lock, enabled by setting
+/// `COLLECTIVE_LOCK_BACKOFF`. Otherwise polls back-to-back.
+pub struct Backoff(u32);
+
+impl Backoff {
+    const MAX: u32 = 1 << 10;
+
+    pub fn new() -> Self {
+        Backoff(1)
+    }
+
+    pub fn spin(&mut self) {
+        self.spin_for(1);
+    }
+
+    /// Back off proportionally to `distance`, e.g. position in a queue.
+    pub fn spin_for(&mut self, distance: u64) {
+        if !*BACKOFF {
+            return;
+        }
+
+        let delay = (self.0 as u64 * distance).min(Self::MAX as u64);
+        for _ in 0..delay {
+            hint::spin_loop();
+        }
+
+        self.0 = (self.0 * 2).min(Self::MAX);
+    }
+}
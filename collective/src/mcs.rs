@@ -0,0 +1,165 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::coherence;
+use crate::lock::Backoff;
+use crate::lock::Lock;
+use crate::metrics;
+use crate::wait;
+use crate::wait::Waiter;
+
+/// MCS queue lock with a queue node per rank.
+///
+/// Waiters enqueue themselves by swapping into the tail and spin on their own
+/// node, so each handoff only touches the cache lines of the releasing and
+/// acquiring ranks. Ranks are stored offset by one, with zero meaning none.
+///
+/// | Tail                |
+/// | Rank 0 Locked, Next |
+/// | Rank 1 Locked, Next |
+/// | ...                 |
+pub struct Mcs<'pci> {
+    lines: &'pci [AtomicU64],
+    rank: usize,
+}
+
+impl<'pci> Mcs<'pci> {
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    const NONE: u64 = 0;
+    const UNLOCKED: u64 = 0;
+    const LOCKED: u64 = 1;
+
+    fn tail(&self) -> &'pci AtomicU64 {
+        &self.lines[0]
+    }
+
+    fn locked(&self, rank: usize) -> &'pci AtomicU64 {
+        &self.lines[(rank + 1) * Self::WORDS]
+    }
+
+    fn next(&self, rank: usize) -> &'pci AtomicU64 {
+        &self.lines[(rank + 1) * Self::WORDS + 1]
+    }
+
+    fn id(&self) -> u64 {
+        self.rank as u64 + 1
+    }
+
+    /// Ranks queued for the lock that no predecessor links to yet, one of
+    /// which swapped itself into the tail after this rank.
+    fn unlinked(&self) -> Vec<ffi::c_int> {
+        let total = self.lines.len() / Self::WORDS - 1;
+        let linked = (0..total)
+            .map(|rank| coherence::load(self.next(rank), Ordering::Acquire))
+            .collect::<Vec<_>>();
+
+        (0..total)
+            .filter(|rank| *rank != self.rank)
+            .filter(|rank| coherence::load(self.locked(*rank), Ordering::Acquire) == Self::LOCKED)
+            .filter(|rank| !linked.contains(&(*rank as u64 + 1)))
+            .map(|rank| rank as ffi::c_int)
+            .collect()
+    }
+}
+
+impl<'pci> Lock<'pci> for Mcs<'pci> {
+    fn size(total: ffi::c_int) -> usize {
+        (total as usize + 1) * crate::CACHE_LINE_SIZE
+    }
+
+    unsafe fn new(address: *const u8, rank: ffi::c_int, total: ffi::c_int) -> Self {
+        Self {
+            lines: slice::from_raw_parts(address.cast(), (total as usize + 1) * Self::WORDS),
+            rank: rank as usize,
+        }
+    }
+
+    fn lock(&self) {
+        coherence::store(self.next(self.rank), Self::NONE, Ordering::Relaxed);
+        coherence::store(self.locked(self.rank), Self::LOCKED, Ordering::Relaxed);
+
+        let predecessor = coherence::swap(self.tail(), self.id(), Ordering::AcqRel);
+
+        // Fast path
+        if predecessor == Self::NONE {
+            // Only queued ranks are left locked, to find them on a stall
+            coherence::store(self.locked(self.rank), Self::UNLOCKED, Ordering::Relaxed);
+            metrics::increment!(metrics::counters::MUTEX_UNCONTENDED);
+            return;
+        }
+
+        coherence::store(
+            self.next(predecessor as usize - 1),
+            self.id(),
+            Ordering::Release,
+        );
+        wait::wake(predecessor as ffi::c_int - 1);
+
+        let mut backoff = Backoff::new();
+        let mut waiter = Waiter::new();
+
+        metrics::time!(metrics::timers::MUTEX, {
+            // Wait for predecessor to hand off
+            while coherence::load(self.locked(self.rank), Ordering::Acquire) == Self::LOCKED {
+                backoff.spin();
+                waiter.wait(|| wait::Stall {
+                    rank: self.rank as ffi::c_int,
+                    word: "mcs lock handoff",
+                    condition: "==",
+                    expected: Self::UNLOCKED,
+                    observed: Self::LOCKED,
+                    missing: Some(vec![predecessor as ffi::c_int - 1]),
+                });
+            }
+        });
+        metrics::increment!(metrics::counters::MUTEX_CONTENDED);
+    }
+
+    fn unlock(&self) {
+        let mut successor = coherence::load(self.next(self.rank), Ordering::Acquire);
+
+        if successor == Self::NONE {
+            if coherence::compare_exchange(
+                self.tail(),
+                self.id(),
+                Self::NONE,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+            {
+                return;
+            }
+
+            // Wait for successor to link itself in
+            wait::until(
+                || {
+                    successor = coherence::load(self.next(self.rank), Ordering::Acquire);
+                    successor != Self::NONE
+                },
+                || wait::Stall {
+                    rank: self.rank as ffi::c_int,
+                    word: "mcs lock successor",
+                    condition: "!=",
+                    expected: Self::NONE,
+                    observed: Self::NONE,
+                    missing: Some(self.unlinked()),
+                },
+            );
+        }
+
+        coherence::store(
+            self.locked(successor as usize - 1),
+            Self::UNLOCKED,
+            Ordering::Release,
+        );
+        wait::wake(successor as ffi::c_int - 1);
+
+        // Nobody links to this rank until it queues again
+        coherence::store(self.next(self.rank), Self::NONE, Ordering::Relaxed);
+    }
+}
@@ -2,6 +2,9 @@
 pub mod counters {
     use std::sync::atomic::AtomicU64;
 
+    pub static BARRIER: AtomicU64 = AtomicU64::new(0);
+    pub static CHECKSUM_MISMATCHED: AtomicU64 = AtomicU64::new(0);
+    pub static CHECKSUM_VERIFIED: AtomicU64 = AtomicU64::new(0);
     pub static MUTEX_CONTENDED: AtomicU64 = AtomicU64::new(0);
     pub static MUTEX_UNCONTENDED: AtomicU64 = AtomicU64::new(0);
 }
@@ -11,6 +14,7 @@ pub mod timers {
     use std::sync::atomic::AtomicU64;
 
     pub static BARRIER: AtomicU64 = AtomicU64::new(0);
+    pub static CHECKSUM: AtomicU64 = AtomicU64::new(0);
     pub static COMPUTE: AtomicU64 = AtomicU64::new(0);
     pub static COPY: AtomicU64 = AtomicU64::new(0);
     pub static MUTEX: AtomicU64 = AtomicU64::new(0);
@@ -88,6 +92,15 @@ pub fn dump() {
     category("copy", &timers::COPY);
     category("compute", &timers::COMPUTE);
     category("barrier", &timers::BARRIER);
+
+    let barrier = counters::BARRIER.load(Ordering::Acquire);
+    let barrier_time = timers::BARRIER.load(Ordering::Acquire);
+    eprintln!(
+        "\tbarrier-{}: {} waits ({:.3}us/wait)",
+        crate::barrier::Barrier::name(),
+        barrier,
+        barrier_time as f64 / 1e3 / barrier as f64,
+    );
     category("mutex", &timers::MUTEX);
     eprintln!(
         "\tmutex-uncontended: {}/{} ({:.2}%)",
@@ -95,19 +108,60 @@ pub fn dump() {
         uncontended + contended,
         uncontended as f64 * 100.0 / ((contended + uncontended) as f64),
     );
+
+    let verified = counters::CHECKSUM_VERIFIED.load(Ordering::Acquire);
+    if verified > 0 {
+        category("checksum", &timers::CHECKSUM);
+        eprintln!(
+            "\tchecksum-mismatched: {}/{} chunks",
+            counters::CHECKSUM_MISMATCHED.load(Ordering::Acquire),
+            verified,
+        );
+    }
+
+    reset();
 }
 
 #[cfg(not(feature = "metrics"))]
 pub fn dump() {}
 
+/// Report anything recorded since the last `dump`, e.g. by broadcasts, which
+/// are not reported per call.
+#[cfg(feature = "metrics")]
+pub fn flush() {
+    use std::sync::atomic::Ordering;
+
+    let recorded = [
+        &counters::BARRIER,
+        &counters::CHECKSUM_VERIFIED,
+        &counters::MUTEX_CONTENDED,
+        &counters::MUTEX_UNCONTENDED,
+        &timers::COPY,
+        &timers::TOTAL,
+    ]
+    .iter()
+    .any(|metric| metric.load(Ordering::Acquire) > 0);
+
+    if recorded {
+        dump();
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn flush() {}
+
 #[cfg(feature = "metrics")]
 pub fn reset() {
     use std::sync::atomic::Ordering;
 
+    counters::BARRIER.store(0, Ordering::Release);
+    counters::CHECKSUM_MISMATCHED.store(0, Ordering::Release);
+    counters::CHECKSUM_VERIFIED.store(0, Ordering::Release);
     counters::MUTEX_CONTENDED.store(0, Ordering::Release);
     counters::MUTEX_UNCONTENDED.store(0, Ordering::Release);
 
     timers::BARRIER.store(0, Ordering::Release);
+    timers::CHECKSUM.store(0, Ordering::Release);
     timers::COPY.store(0, Ordering::Release);
     timers::COMPUTE.store(0, Ordering::Release);
     timers::MUTEX.store(0, Ordering::Release);
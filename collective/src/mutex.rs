@@ -1,53 +1,93 @@
+use std::ffi;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 
+use crate::coherence;
+use crate::lock::Backoff;
+use crate::lock::Lock;
 use crate::metrics;
+use crate::wait;
+use crate::wait::Waiter;
 
-pub struct Mutex<'pci>(&'pci AtomicU64);
+/// Test-and-test-and-set lock, whose state holds the holder's rank offset by
+/// one, with zero meaning unlocked.
+pub struct Mutex<'pci> {
+    state: &'pci AtomicU64,
+    rank: ffi::c_int,
+    total: ffi::c_int,
+}
 
 impl<'pci> Mutex<'pci> {
-    pub const SIZE: usize = crate::CACHE_LINE_SIZE;
-
     const UNLOCKED: u64 = 0;
-    const LOCKED: u64 = 1;
 
-    pub unsafe fn new(address: *const u8) -> Self {
-        Self(&*address.cast::<AtomicU64>())
+    fn id(&self) -> u64 {
+        self.rank as u64 + 1
+    }
+}
+
+impl<'pci> Lock<'pci> for Mutex<'pci> {
+    fn size(_: ffi::c_int) -> usize {
+        crate::CACHE_LINE_SIZE
     }
 
-    pub fn lock(&self) {
+    unsafe fn new(address: *const u8, rank: ffi::c_int, total: ffi::c_int) -> Self {
+        Self {
+            state: &*address.cast::<AtomicU64>(),
+            rank,
+            total,
+        }
+    }
+
+    fn lock(&self) {
         // Fast path
-        if self
-            .0
-            .compare_exchange(
-                Self::UNLOCKED,
-                Self::LOCKED,
-                Ordering::AcqRel,
-                Ordering::Acquire,
-            )
-            .is_ok()
+        if coherence::compare_exchange(
+            self.state,
+            Self::UNLOCKED,
+            self.id(),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_ok()
         {
             metrics::increment!(metrics::counters::MUTEX_UNCONTENDED);
             return;
         }
 
+        let mut backoff = Backoff::new();
+        let mut waiter = Waiter::new();
+
         metrics::time!(metrics::timers::MUTEX, {
-            while self.0.load(Ordering::Acquire) == Self::LOCKED
-                || self
-                    .0
-                    .compare_exchange(
-                        Self::UNLOCKED,
-                        Self::LOCKED,
-                        Ordering::AcqRel,
-                        Ordering::Acquire,
-                    )
-                    .is_err()
-            {}
+            while coherence::load(self.state, Ordering::Acquire) != Self::UNLOCKED
+                || coherence::compare_exchange(
+                    self.state,
+                    Self::UNLOCKED,
+                    self.id(),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                backoff.spin();
+                waiter.wait(|| {
+                    let state = coherence::load(self.state, Ordering::Acquire);
+                    wait::Stall {
+                        rank: self.rank,
+                        word: "ttas lock state",
+                        condition: "==",
+                        expected: Self::UNLOCKED,
+                        observed: state,
+                        missing: state
+                            .checked_sub(1)
+                            .map(|holder| vec![holder as ffi::c_int]),
+                    }
+                });
+            }
         });
         metrics::increment!(metrics::counters::MUTEX_CONTENDED);
     }
 
-    pub fn unlock(&self) {
-        self.0.store(Self::UNLOCKED, Ordering::Release);
+    fn unlock(&self) {
+        coherence::store(self.state, Self::UNLOCKED, Ordering::Release);
+        wait::wake_all(self.rank, self.total);
     }
 }
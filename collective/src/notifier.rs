@@ -0,0 +1,173 @@
+//! Backends for blocking until a peer signals, selected by
+//! `COLLECTIVE_NOTIFIER`:
+//!
+//! - `doorbell`: block on a zero-length `read` of the shared memory file, once
+//!   `poll` finds it readable within the timeout, and ring a peer by
+//!   `pwrite`-ing its `u16` ID, as exposed by the ivshmem doorbell driver.
+//! - `futex`: block on a futex word in the segment header, for ranks sharing
+//!   a host (e.g. with `COLLECTIVE_PCI_PATH` pointing into `/dev/shm`).
+
+use std::env;
+use std::ffi;
+use std::os::fd::AsRawFd as _;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::segment;
+
+pub trait Notifier: Send + Sync {
+    /// Snapshot taken before polling, and passed to the following `wait`, so
+    /// that notifications between the poll and `wait` are not lost.
+    fn prepare(&self) -> u32 {
+        0
+    }
+
+    /// Block until notified after `token` was taken, or until `timeout` if
+    /// the backend supports it. May return spuriously.
+    fn wait(&self, token: u32, timeout: Option<Duration>);
+
+    /// Wake `rank` if it is blocked in `wait`.
+    fn notify(&self, rank: ffi::c_int);
+
+    /// Wake every rank except `exclude` if they are blocked in `wait`.
+    fn notify_all(&self, exclude: ffi::c_int, total: ffi::c_int) {
+        (0..total)
+            .filter(|rank| *rank != exclude)
+            .for_each(|rank| self.notify(rank));
+    }
+
+    /// Forget any shared memory, before it is unmapped. Later calls return at
+    /// once.
+    #[cfg(feature = "mpi")]
+    fn stop(&self) {}
+}
+
+/// Must be forced before any collective locks the shared memory map.
+pub static NOTIFIER: Lazy<Box<dyn Notifier>> =
+    Lazy::new(|| match env::var("COLLECTIVE_NOTIFIER").as_deref() {
+        Ok("doorbell") | Err(_) => Box::new(Doorbell),
+        Ok("futex") => {
+            let mut map = crate::pci().lock();
+            Box::new(unsafe { Futex::new(map[segment::NOTIFIER_OFFSET..].as_mut_ptr()) })
+        }
+        Ok(notifier) => panic!("Unknown notifier: {}", notifier),
+    });
+
+/// ivshmem doorbell, via the driver backing the shared memory file.
+pub struct Doorbell;
+
+impl Notifier for Doorbell {
+    fn wait(&self, _: u32, timeout: Option<Duration>) {
+        let fd = crate::pci().file.as_raw_fd();
+
+        // Only read once the doorbell has rung, so the read cannot outlast the
+        // timeout
+        if let Some(timeout) = timeout {
+            let mut poll = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Rounded up, so as not to spin on a timeout under a millisecond
+            let milliseconds = timeout.as_nanos().div_ceil(1_000_000);
+            let milliseconds = milliseconds.min(ffi::c_int::MAX as u128) as ffi::c_int;
+
+            // Timed out, or interrupted, which is a spurious return
+            if unsafe { libc::poll(&mut poll, 1, milliseconds) } <= 0 {
+                return;
+            }
+        }
+
+        unsafe {
+            assert_eq!(libc::read(fd, std::ptr::null_mut(), 0), 0);
+        }
+    }
+
+    fn notify(&self, rank: ffi::c_int) {
+        unsafe {
+            assert_eq!(
+                libc::pwrite(
+                    crate::pci().file.as_raw_fd(),
+                    (rank as u16).to_ne_bytes().as_ptr().cast(),
+                    std::mem::size_of::<u16>(),
+                    0,
+                ),
+                2,
+            );
+        }
+    }
+}
+
+/// Single futex word shared by all ranks on the same host.
+///
+/// Every notification bumps the word and wakes all waiters, which then poll
+/// their own condition again.
+pub struct Futex(AtomicPtr<AtomicU32>);
+
+impl Futex {
+    /// Requires `address` to stay mapped (shared) until `stop`.
+    unsafe fn new(address: *mut u8) -> Self {
+        Self(AtomicPtr::new(address.cast()))
+    }
+
+    /// The futex word, unless forgotten by `stop`.
+    fn word(&self) -> Option<&AtomicU32> {
+        unsafe { self.0.load(Ordering::Acquire).as_ref() }
+    }
+
+    fn wake(&self) {
+        let Some(word) = self.word() else {
+            return;
+        };
+        word.fetch_add(1, Ordering::Release);
+        unsafe {
+            libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX);
+        }
+    }
+}
+
+impl Notifier for Futex {
+    fn prepare(&self) -> u32 {
+        self.word().map_or(0, |word| word.load(Ordering::Acquire))
+    }
+
+    fn wait(&self, token: u32, timeout: Option<Duration>) {
+        let Some(word) = self.word() else {
+            return;
+        };
+        let timeout = timeout.map(|timeout| libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        });
+
+        // Returns immediately with `EAGAIN` if the word has already changed
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                word.as_ptr(),
+                libc::FUTEX_WAIT,
+                token,
+                timeout
+                    .as_ref()
+                    .map_or(std::ptr::null(), |timeout| timeout as *const libc::timespec),
+            );
+        }
+    }
+
+    fn notify(&self, _: ffi::c_int) {
+        self.wake();
+    }
+
+    fn notify_all(&self, _: ffi::c_int, _: ffi::c_int) {
+        self.wake();
+    }
+
+    #[cfg(feature = "mpi")]
+    fn stop(&self) {
+        self.0.store(std::ptr::null_mut(), Ordering::Release);
+    }
+}
@@ -0,0 +1,524 @@
+//! Lossy storage formats for f32 allreduce contributions, enabled by setting
+//! `COLLECTIVE_ALLREDUCE_QUANTIZE` to `bf16`, `fp16` or `int8`.
+//!
+//! Halves (or quarters) the bytes moved through the segment, at the cost of
+//! precision: contributions and partial results are stored quantized, but
+//! summed in f32. `int8` stores one f32 scale per `BLOCK` elements, mapping
+//! the largest finite magnitude in the block to 127. It cannot represent
+//! infinities or NaN, which decode as NaN without affecting the rest of their
+//! block.
+
+use std::env;
+
+use once_cell::sync::Lazy;
+
+use crate::kernel;
+
+/// Elements sharing one `int8` scale.
+pub const BLOCK: usize = 256;
+
+/// `int8` value of non-finite elements, which finite ones never round to.
+const NON_FINITE: i8 = i8::MIN;
+
+pub static FORMAT: Lazy<Option<Format>> =
+    Lazy::new(
+        || match env::var("COLLECTIVE_ALLREDUCE_QUANTIZE").as_deref() {
+            Err(_) => None,
+            Ok("bf16") => Some(Format::Bf16),
+            Ok("fp16") => Some(Format::Fp16),
+            Ok("int8") => Some(Format::Int8),
+            Ok(format) => panic!("Unknown quantization format: {}", format),
+        },
+    );
+
+#[cfg(target_arch = "x86_64")]
+static F16C: Lazy<bool> =
+    Lazy::new(|| is_x86_feature_detected!("f16c") && is_x86_feature_detected!("avx"));
+
+#[derive(Copy, Clone, Debug)]
+pub enum Format {
+    Bf16,
+    Fp16,
+    Int8,
+}
+
+impl Format {
+    /// Bytes per stored element.
+    pub fn size(self) -> usize {
+        match self {
+            Format::Bf16 | Format::Fp16 => 2,
+            Format::Int8 => 1,
+        }
+    }
+
+    /// Number of scales covering `count` elements.
+    pub fn scales(self, count: usize) -> usize {
+        match self {
+            Format::Bf16 | Format::Fp16 => 0,
+            Format::Int8 => count.div_ceil(BLOCK),
+        }
+    }
+
+    /// Quantize `data` into `values` and `scales`.
+    pub fn encode(self, data: &[f32], values: &mut [u8], scales: &mut [f32]) {
+        assert_eq!(values.len(), data.len() * self.size());
+        assert_eq!(scales.len(), self.scales(data.len()));
+
+        match self {
+            Format::Bf16 => {
+                data.iter()
+                    .zip(values.chunks_exact_mut(2))
+                    .for_each(|(data, value)| {
+                        value.copy_from_slice(&bf16::encode(*data).to_ne_bytes())
+                    })
+            }
+            Format::Fp16 => fp16::encode(data, values),
+            Format::Int8 => data
+                .chunks(BLOCK)
+                .zip(values.chunks_mut(BLOCK))
+                .zip(scales)
+                .for_each(|((data, values), scale)| {
+                    let max = data
+                        .iter()
+                        .filter(|data| data.is_finite())
+                        .fold(0.0f32, |max, data| max.max(data.abs()));
+                    *scale = max / i8::MAX as f32;
+                    let inverse = match max {
+                        0.0 => 0.0,
+                        _ => 1.0 / *scale,
+                    };
+                    data.iter().zip(values).for_each(|(data, value)| {
+                        *value = match data.is_finite() {
+                            true => (data * inverse).round() as i8,
+                            false => NON_FINITE,
+                        } as u8;
+                    });
+                }),
+        }
+    }
+
+    /// Element-wise `sum += decode(values, scales)`, a chunk at a time.
+    pub fn accumulate(self, values: &[u8], scales: &[f32], sum: &mut [f32]) {
+        const CHUNK: usize = 4 * BLOCK;
+
+        let mut decoded = [0.0; CHUNK];
+        for (index, sum) in sum.chunks_mut(CHUNK).enumerate() {
+            let decoded = &mut decoded[..sum.len()];
+            let values = &values[index * CHUNK * self.size()..][..sum.len() * self.size()];
+            let scales = &scales[self.scales(index * CHUNK)..][..self.scales(sum.len())];
+            self.decode(values, scales, decoded);
+            kernel::f32::sum(sum, decoded);
+        }
+    }
+
+    /// Dequantize `values` and `scales` into `data`.
+    pub fn decode(self, values: &[u8], scales: &[f32], data: &mut [f32]) {
+        assert_eq!(values.len(), data.len() * self.size());
+        assert_eq!(scales.len(), self.scales(data.len()));
+
+        match self {
+            Format::Bf16 => {
+                data.iter_mut()
+                    .zip(values.chunks_exact(2))
+                    .for_each(|(data, value)| {
+                        *data = bf16::decode(u16::from_ne_bytes([value[0], value[1]]))
+                    })
+            }
+            Format::Fp16 => fp16::decode(values, data),
+            Format::Int8 => data
+                .chunks_mut(BLOCK)
+                .zip(values.chunks(BLOCK))
+                .zip(scales)
+                .for_each(|((data, values), scale)| {
+                    data.iter_mut().zip(values).for_each(|(data, value)| {
+                        *data = match *value as i8 {
+                            NON_FINITE => f32::NAN,
+                            value => value as f32 * scale,
+                        }
+                    })
+                }),
+        }
+    }
+}
+
+/// Upper half of an f32, rounded to nearest even.
+mod bf16 {
+    pub fn encode(value: f32) -> u16 {
+        let bits = value.to_bits();
+        if value.is_nan() {
+            // Keep NaN quiet, rather than rounding it to infinity
+            return ((bits >> 16) | 0x40) as u16;
+        }
+        let rounding = 0x7FFF + ((bits >> 16) & 1);
+        (bits.wrapping_add(rounding) >> 16) as u16
+    }
+
+    pub fn decode(value: u16) -> f32 {
+        f32::from_bits((value as u32) << 16)
+    }
+}
+
+/// IEEE 754 half precision, using F16C where available.
+mod fp16 {
+    pub fn encode(data: &[f32], values: &mut [u8]) {
+        #[cfg(target_arch = "x86_64")]
+        if *super::F16C {
+            return unsafe { encode_f16c(data, values) };
+        }
+
+        encode_scalar(data, values)
+    }
+
+    pub fn decode(values: &[u8], data: &mut [f32]) {
+        #[cfg(target_arch = "x86_64")]
+        if *super::F16C {
+            return unsafe { decode_f16c(values, data) };
+        }
+
+        decode_scalar(values, data)
+    }
+
+    fn encode_scalar(data: &[f32], values: &mut [u8]) {
+        data.iter()
+            .zip(values.chunks_exact_mut(2))
+            .for_each(|(data, value)| value.copy_from_slice(&encode_one(*data).to_ne_bytes()));
+    }
+
+    fn decode_scalar(values: &[u8], data: &mut [f32]) {
+        data.iter_mut()
+            .zip(values.chunks_exact(2))
+            .for_each(|(data, value)| *data = decode_one(u16::from_ne_bytes([value[0], value[1]])));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "f16c,avx")]
+    unsafe fn encode_f16c(data: &[f32], values: &mut [u8]) {
+        use std::arch::x86_64::*;
+
+        let split = data.len() / 8 * 8;
+        let (data_head, data_tail) = data.split_at(split);
+        let (values_head, values_tail) = values.split_at_mut(split * 2);
+
+        data_head
+            .chunks_exact(8)
+            .zip(values_head.chunks_exact_mut(16))
+            .for_each(|(data, values)| {
+                let half =
+                    _mm256_cvtps_ph::<_MM_FROUND_TO_NEAREST_INT>(_mm256_loadu_ps(data.as_ptr()));
+                _mm_storeu_si128(values.as_mut_ptr().cast(), half);
+            });
+
+        encode_scalar(data_tail, values_tail);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "f16c,avx")]
+    unsafe fn decode_f16c(values: &[u8], data: &mut [f32]) {
+        use std::arch::x86_64::*;
+
+        let split = data.len() / 8 * 8;
+        let (data_head, data_tail) = data.split_at_mut(split);
+        let (values_head, values_tail) = values.split_at(split * 2);
+
+        data_head
+            .chunks_exact_mut(8)
+            .zip(values_head.chunks_exact(16))
+            .for_each(|(data, values)| {
+                let single = _mm256_cvtph_ps(_mm_loadu_si128(values.as_ptr().cast()));
+                _mm256_storeu_ps(data.as_mut_ptr(), single);
+            });
+
+        decode_scalar(values_tail, data_tail);
+    }
+
+    fn encode_one(value: f32) -> u16 {
+        let bits = value.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exponent = ((bits >> 23) & 0xFF) as i32;
+        let mantissa = bits & 0x7F_FFFF;
+
+        // Infinity and NaN
+        if exponent == 0xFF {
+            return sign
+                | 0x7C00
+                | if mantissa == 0 {
+                    0
+                } else {
+                    0x200 | (mantissa >> 13) as u16
+                };
+        }
+
+        let exponent = exponent - 127 + 15;
+        if exponent >= 0x1F {
+            return sign | 0x7C00;
+        }
+
+        // Subnormal, shifting in the implicit leading bit
+        if exponent <= 0 {
+            if exponent < -10 {
+                return sign;
+            }
+            let mantissa = mantissa | 0x80_0000;
+            let shift = (14 - exponent) as u32;
+            let rounding = (1 << (shift - 1)) - 1 + ((mantissa >> shift) & 1);
+            return sign | ((mantissa + rounding) >> shift) as u16;
+        }
+
+        // Rounding may carry into the exponent, up to infinity
+        let rounding = 0xFFF + ((mantissa >> 13) & 1);
+        let half = ((exponent as u32) << 10) + ((mantissa + rounding) >> 13);
+        sign | half.min(0x7C00) as u16
+    }
+
+    fn decode_one(value: u16) -> f32 {
+        let sign = ((value & 0x8000) as u32) << 16;
+        let exponent = ((value >> 10) & 0x1F) as u32;
+        let mantissa = (value & 0x3FF) as u32;
+
+        let bits = match exponent {
+            0 => {
+                let magnitude = mantissa as f32 / (1 << 24) as f32;
+                return f32::from_bits(sign | magnitude.to_bits());
+            }
+            0x1F => sign | 0x7F80_0000 | (mantissa << 13),
+            _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+        };
+        f32::from_bits(bits)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        /// The scalar fallback agrees with F16C, where available.
+        #[test]
+        fn scalar_matches() {
+            let nan = |half: &[u8]| u16::from_ne_bytes([half[0], half[1]]) & 0x7FFF > 0x7C00;
+
+            let halves = (0..=u16::MAX)
+                .flat_map(|half| half.to_ne_bytes())
+                .collect::<Vec<_>>();
+            let mut scalar = vec![0.0; halves.len() / 2];
+            let mut dispatched = vec![0.0; halves.len() / 2];
+            super::decode_scalar(&halves, &mut scalar);
+            super::decode(&halves, &mut dispatched);
+            for (scalar, dispatched) in scalar.iter().zip(&dispatched) {
+                assert!(
+                    scalar.to_bits() == dispatched.to_bits()
+                        || scalar.is_nan() && dispatched.is_nan()
+                );
+            }
+
+            let data = (0..=u32::MAX)
+                .step_by(4093)
+                .map(f32::from_bits)
+                .collect::<Vec<_>>();
+            let mut scalar = vec![0; data.len() * 2];
+            let mut dispatched = vec![0; data.len() * 2];
+            super::encode_scalar(&data, &mut scalar);
+            super::encode(&data, &mut dispatched);
+            for (scalar, dispatched) in scalar.chunks_exact(2).zip(dispatched.chunks_exact(2)) {
+                assert!(scalar == dispatched || nan(scalar) && nan(dispatched));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(format: Format, data: &[f32]) -> (Vec<u8>, Vec<f32>) {
+        let mut values = vec![0; data.len() * format.size()];
+        let mut scales = vec![0.0; format.scales(data.len())];
+        format.encode(data, &mut values, &mut scales);
+        (values, scales)
+    }
+
+    fn decode(format: Format, values: &[u8], scales: &[f32]) -> Vec<f32> {
+        let mut data = vec![0.0; values.len() / format.size()];
+        format.decode(values, scales, &mut data);
+        data
+    }
+
+    fn halves(format: Format, data: &[f32]) -> Vec<u16> {
+        encode(format, data)
+            .0
+            .chunks_exact(2)
+            .map(|half| u16::from_ne_bytes([half[0], half[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn bf16_known_answers() {
+        let data = [
+            1.0,
+            -2.0,
+            // Ties round to even, down then up
+            f32::from_bits(0x3F80_8000),
+            f32::from_bits(0x3F81_8000),
+            f32::from_bits(0x3F80_8001),
+            f32::MAX,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::from_bits(1),
+        ];
+        assert_eq!(
+            halves(Format::Bf16, &data),
+            [0x3F80, 0xC000, 0x3F80, 0x3F82, 0x3F81, 0x7F80, 0x7F80, 0xFF80, 0x0000],
+        );
+
+        let nan = halves(Format::Bf16, &[f32::NAN, f32::from_bits(0x7F80_0001)]);
+        assert!(nan
+            .iter()
+            .all(|nan| nan & 0x7F80 == 0x7F80 && nan & 0x7F != 0));
+    }
+
+    #[test]
+    fn bf16_round_trip() {
+        let data = (0..=u16::MAX)
+            .map(|bits| f32::from_bits((bits as u32) << 16))
+            .collect::<Vec<_>>();
+        let (values, scales) = encode(Format::Bf16, &data);
+        let decoded = decode(Format::Bf16, &values, &scales);
+
+        for (data, decoded) in data.iter().zip(&decoded) {
+            match data.is_nan() {
+                true => assert!(decoded.is_nan()),
+                false => assert_eq!(data.to_bits(), decoded.to_bits()),
+            }
+        }
+    }
+
+    #[test]
+    fn fp16_known_answers() {
+        let data = [
+            1.0,
+            -2.0,
+            65504.0,
+            // Halfway to the next finite value rounds up to infinity
+            65520.0,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            -0.0,
+            // Smallest subnormal, then ties to even around it
+            2.0f32.powi(-24),
+            2.0f32.powi(-25),
+            3.0 * 2.0f32.powi(-25),
+            2.0f32.powi(-26),
+            // Largest subnormal, then rounding up into the normals
+            1023.0 * 2.0f32.powi(-24),
+            1023.5 * 2.0f32.powi(-24),
+            // Ties round to even
+            1.0 + 2.0f32.powi(-11),
+            1.0 + 3.0 * 2.0f32.powi(-11),
+        ];
+        assert_eq!(
+            halves(Format::Fp16, &data),
+            [
+                0x3C00, 0xC000, 0x7BFF, 0x7C00, 0x7C00, 0xFC00, 0x8000, 0x0001, 0x0000, 0x0002,
+                0x0000, 0x03FF, 0x0400, 0x3C00, 0x3C02,
+            ],
+        );
+
+        let nan = halves(Format::Fp16, &[f32::NAN]);
+        assert!(nan[0] & 0x7C00 == 0x7C00 && nan[0] & 0x3FF != 0);
+    }
+
+    #[test]
+    fn fp16_round_trip() {
+        let halves = (0..=u16::MAX).collect::<Vec<_>>();
+        let values = halves
+            .iter()
+            .flat_map(|half| half.to_ne_bytes())
+            .collect::<Vec<_>>();
+        let decoded = decode(Format::Fp16, &values, &[]);
+        let encoded = self::halves(Format::Fp16, &decoded);
+
+        for ((half, decoded), encoded) in halves.iter().zip(&decoded).zip(&encoded) {
+            match half & 0x7C00 == 0x7C00 && half & 0x3FF != 0 {
+                true => assert!(decoded.is_nan()),
+                false => assert_eq!(half, encoded, "{:#06x} decoded as {}", half, decoded),
+            }
+        }
+    }
+
+    #[test]
+    fn fp16_unaligned_lengths() {
+        // Exercises both the vectorized body and the scalar tail
+        let data = (0..27)
+            .map(|index| index as f32 * 0.25 - 3.0)
+            .collect::<Vec<_>>();
+        let (values, scales) = encode(Format::Fp16, &data);
+        assert_eq!(decode(Format::Fp16, &values, &scales), data);
+    }
+
+    #[test]
+    fn int8_zero_block() {
+        let data = vec![0.0; BLOCK + 1];
+        let (values, scales) = encode(Format::Int8, &data);
+        assert_eq!(scales, [0.0, 0.0]);
+        assert_eq!(decode(Format::Int8, &values, &scales), data);
+    }
+
+    #[test]
+    fn int8_round_trip() {
+        let data = (0..3 * BLOCK / 2)
+            .map(|index| ((index * 37 % 101) as f32 - 50.0) * 0.125)
+            .collect::<Vec<_>>();
+        let (values, scales) = encode(Format::Int8, &data);
+        let decoded = decode(Format::Int8, &values, &scales);
+
+        for (index, (data, decoded)) in data.iter().zip(&decoded).enumerate() {
+            let scale = scales[index / BLOCK];
+            assert!(
+                (data - decoded).abs() <= scale / 2.0,
+                "{} decoded as {}",
+                data,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn int8_non_finite_block() {
+        let mut data = (0..BLOCK).map(|index| index as f32).collect::<Vec<_>>();
+        data[1] = f32::INFINITY;
+        data[2] = f32::NEG_INFINITY;
+        data[3] = f32::NAN;
+
+        let (values, scales) = encode(Format::Int8, &data);
+        assert_eq!(scales[0], (BLOCK - 1) as f32 / i8::MAX as f32);
+
+        let decoded = decode(Format::Int8, &values, &scales);
+        assert!(decoded[1..4].iter().all(|decoded| decoded.is_nan()));
+        for (data, decoded) in data
+            .iter()
+            .zip(&decoded)
+            .filter(|(data, _)| data.is_finite())
+        {
+            assert!(
+                (data - decoded).abs() <= scales[0] / 2.0,
+                "{} decoded as {}",
+                data,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn accumulate_chunks() {
+        for format in [Format::Bf16, Format::Fp16, Format::Int8] {
+            let data = (0..5 * BLOCK + 3)
+                .map(|index| (index % 7) as f32)
+                .collect::<Vec<_>>();
+            let (values, scales) = encode(format, &data);
+            let mut sum = vec![1.0; data.len()];
+            format.accumulate(&values, &scales, &mut sum);
+
+            let expected = decode(format, &values, &scales);
+            assert!(sum
+                .iter()
+                .zip(&expected)
+                .all(|(sum, expected)| *sum == expected + 1.0));
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::barrier::Barrier;
+use crate::coherence;
+use crate::liveness;
+use crate::signature;
+use crate::wait;
+
+// | Barrier             |
+// | Slot 0 Ready        |
+// | Slot 1 Ready        |
+// | Notifier            |
+// | Rank 0 Sequence     |
+// | Rank 1 Sequence     |
+// | ...                 |
+// | Liveness            |
+// | Signatures          |
+// | Slot 0              | <- Even calls
+// | Slot 1              | <- Odd calls
+pub const HEADER_SIZE: usize = Barrier::SIZE + crate::PAGE_SIZE * 3;
+
+const BARRIER_OFFSET: usize = 0;
+const READY_OFFSET: usize = BARRIER_OFFSET + Barrier::SIZE;
+pub const NOTIFIER_OFFSET: usize = READY_OFFSET + crate::CACHE_LINE_SIZE * 2;
+const SEQUENCE_OFFSET: usize = NOTIFIER_OFFSET + crate::CACHE_LINE_SIZE;
+pub const LIVENESS_OFFSET: usize = READY_OFFSET + crate::PAGE_SIZE;
+pub const LIVENESS_SIZE: usize = crate::PAGE_SIZE;
+const SIGNATURE_OFFSET: usize = LIVENESS_OFFSET + LIVENESS_SIZE;
+
+/// Most ranks with a sequence line in the header, which has room for fewer
+/// ranks than any other per-rank area.
+pub const MAX_RANKS: usize = (LIVENESS_OFFSET - SEQUENCE_OFFSET) / crate::CACHE_LINE_SIZE;
+
+/// Smallest map holding the header and two non-empty slots.
+pub const MIN_SIZE: usize = HEADER_SIZE + crate::PAGE_SIZE * 2;
+
+/// Bytes of each slot in a map of `size` bytes, in whole pages.
+pub fn slot_size(size: usize) -> usize {
+    (size.saturating_sub(HEADER_SIZE) / 2) & !(crate::PAGE_SIZE - 1)
+}
+
+/// Calls completed by a rank that has left its group.
+const LEFT: u64 = u64::MAX;
+
+/// Calls started by this process.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Shared memory header for a single collective call.
+///
+/// Collectives alternate between two slots by call sequence number, so ranks
+/// copying out of call N don't have to be waited on before contributing to
+/// call N + 1. Each rank publishes the number of calls it has completed, and
+/// a slot is only reused once every rank has completed its previous call.
+pub struct Segment<'pci> {
+    header: &'pci [u8],
+    sequence: u64,
+    rank: ffi::c_int,
+    total: ffi::c_int,
+}
+
+impl<'pci> Segment<'pci> {
+    /// Splits the shared memory map into the header and the slot for this call.
+    ///
+    /// Requires first `HEADER_SIZE` bytes to be zero-initialized.
+    pub fn split(
+        map: &'pci mut [u8],
+        rank: ffi::c_int,
+        total: ffi::c_int,
+    ) -> (Self, &'pci mut [u8]) {
+        let sequence = SEQUENCE.fetch_add(1, Ordering::AcqRel);
+        liveness::enter(sequence);
+
+        let slot_size = slot_size(map.len());
+        let (header, remainder) = map.split_at_mut(HEADER_SIZE);
+        let slot = &mut remainder[(sequence % 2) as usize * slot_size..][..slot_size];
+
+        let segment = Self {
+            header,
+            sequence,
+            rank,
+            total,
+        };
+
+        if signature::enabled() {
+            // Peers may still be reading this rank's signature of the
+            // previous call, even once its slot can be reserved
+            segment.settle(sequence);
+            signature::verify(
+                segment.words(SIGNATURE_OFFSET, crate::PAGE_SIZE),
+                sequence,
+                rank,
+                total,
+            );
+        }
+
+        (segment, slot)
+    }
+
+    /// Ranks that have not completed as many calls as this one, e.g. because
+    /// they are still inside a collective, or skipped one.
+    #[cfg(feature = "mpi")]
+    pub fn pending(map: &'pci [u8], rank: ffi::c_int, total: ffi::c_int) -> Vec<ffi::c_int> {
+        let segment = Self {
+            header: &map[..HEADER_SIZE],
+            sequence: SEQUENCE.load(Ordering::Acquire),
+            rank,
+            total,
+        };
+
+        (0..total)
+            .filter(|peer| {
+                coherence::load(segment.completed(*peer), Ordering::Acquire) != segment.sequence
+            })
+            .collect()
+    }
+
+    /// Leave a group without an outside barrier: wait until every rank has
+    /// completed as many calls as this one, or return the ranks that
+    /// completed more. Each rank then finalizes liveness and marks itself
+    /// left, and rank 0 waits for every rank to leave before calling `reset`.
+    pub fn leave(map: &mut [u8], rank: ffi::c_int, total: ffi::c_int) -> Vec<ffi::c_int> {
+        let segment = Segment {
+            header: &map[..HEADER_SIZE],
+            sequence: SEQUENCE.load(Ordering::Acquire),
+            rank,
+            total,
+        };
+
+        segment.settle(segment.sequence);
+        let ahead = (0..total)
+            .filter(|peer| {
+                let completed = coherence::load(segment.completed(*peer), Ordering::Acquire);
+                completed != segment.sequence && completed != LEFT
+            })
+            .collect::<Vec<_>>();
+        if !ahead.is_empty() {
+            return ahead;
+        }
+
+        // Nothing writes this rank's liveness words once it has left
+        liveness::finalize();
+        liveness::stop();
+        coherence::store(segment.completed(rank), LEFT, Ordering::Release);
+        wait::wake_all(rank, total);
+
+        if rank == 0 {
+            for peer in 0..total {
+                wait::until(
+                    || coherence::load(segment.completed(peer), Ordering::Acquire) == LEFT,
+                    // Peers finalize liveness before leaving, so only check
+                    // that they are alive
+                    || wait::Stall {
+                        rank,
+                        word: "group leave",
+                        condition: "==",
+                        expected: LEFT,
+                        observed: coherence::load(segment.completed(peer), Ordering::Acquire),
+                        missing: None,
+                    },
+                );
+            }
+            Segment::reset(map);
+        }
+
+        Vec::new()
+    }
+
+    /// Zero the header, as required by the next job to `split` this map. No
+    /// rank may use the header afterwards.
+    pub fn reset(map: &mut [u8]) {
+        let header = &mut map[..HEADER_SIZE];
+        header.fill(0);
+        coherence::flush(header);
+    }
+
+    pub fn barrier(&self) -> Barrier<'pci> {
+        unsafe { Barrier::new(self.header[BARRIER_OFFSET..].as_ptr()) }
+    }
+
+    /// Wait until every rank is done with the previous call using this slot.
+    pub fn reserve(&self) {
+        self.settle(self.sequence.saturating_sub(1));
+    }
+
+    /// Wait until every rank has completed `previous` calls.
+    fn settle(&self, previous: u64) {
+        for rank in 0..self.total {
+            wait::until(
+                || coherence::load(self.completed(rank), Ordering::Acquire) >= previous,
+                || wait::Stall {
+                    rank: self.rank,
+                    word: "slot completion",
+                    condition: ">=",
+                    expected: previous,
+                    observed: coherence::load(self.completed(rank), Ordering::Acquire),
+                    missing: Some(
+                        (0..self.total)
+                            .filter(|rank| {
+                                coherence::load(self.completed(*rank), Ordering::Acquire) < previous
+                            })
+                            .collect(),
+                    ),
+                },
+            );
+        }
+    }
+
+    /// Mark this slot as written for this call.
+    pub fn publish(&self) {
+        coherence::store(self.ready(), self.sequence + 1, Ordering::Release);
+        wait::wake_all(self.rank, self.total);
+    }
+
+    /// Wait until `root` has written this slot for this call.
+    pub fn wait(&self, root: ffi::c_int) {
+        wait::until(
+            || coherence::load(self.ready(), Ordering::Acquire) > self.sequence,
+            || wait::Stall {
+                rank: self.rank,
+                word: "slot ready",
+                condition: ">",
+                expected: self.sequence,
+                observed: coherence::load(self.ready(), Ordering::Acquire),
+                missing: Some(vec![root]),
+            },
+        );
+    }
+
+    /// Mark this call as completed, releasing the slot for reuse.
+    pub fn complete(self) {
+        coherence::store(
+            self.completed(self.rank),
+            self.sequence + 1,
+            Ordering::Release,
+        );
+        wait::wake_all(self.rank, self.total);
+        liveness::leave();
+    }
+
+    fn words(&self, offset: usize, size: usize) -> &'pci [AtomicU64] {
+        let words = &self.header[offset..][..size];
+
+        // https://doc.rust-lang.org/src/core/sync/atomic.rs.html#2090-2092
+        unsafe { slice::from_raw_parts(words.as_ptr().cast(), size / mem::size_of::<AtomicU64>()) }
+    }
+
+    fn ready(&self) -> &'pci AtomicU64 {
+        let offset = READY_OFFSET + (self.sequence % 2) as usize * crate::CACHE_LINE_SIZE;
+
+        // https://doc.rust-lang.org/src/core/sync/atomic.rs.html#2090-2092
+        unsafe { &*self.header[offset..].as_ptr().cast::<AtomicU64>() }
+    }
+
+    fn completed(&self, rank: ffi::c_int) -> &'pci AtomicU64 {
+        let offset = SEQUENCE_OFFSET + rank as usize * crate::CACHE_LINE_SIZE;
+        assert!(
+            offset < LIVENESS_OFFSET,
+            "Too many ranks for segment header"
+        );
+
+        // https://doc.rust-lang.org/src/core/sync/atomic.rs.html#2090-2092
+        unsafe { &*self.header[offset..].as_ptr().cast::<AtomicU64>() }
+    }
+}
@@ -0,0 +1,238 @@
+//! Debug checking that every rank calls the same collective with the same
+//! arguments, enabled by setting `COLLECTIVE_CHECK_SIGNATURES`.
+//!
+//! Before touching the slot, each rank publishes a signature of its call into
+//! its own line of the segment header, then compares every peer's signature
+//! against rank 0's, aborting with the offending ranks on mismatch. Only one
+//! signature per rank is needed, since ranks first wait for every rank to
+//! complete the previous call.
+//!
+//! | Rank 0 Signature    |
+//! | Rank 1 Signature    |
+//! | ...                 |
+
+use std::cell::Cell;
+use std::env;
+use std::ffi;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use once_cell::sync::Lazy;
+
+use crate::coherence;
+use crate::wait;
+
+static ENABLED: Lazy<bool> = Lazy::new(|| env::var("COLLECTIVE_CHECK_SIGNATURES").is_ok());
+
+thread_local! {
+    static CURRENT: Cell<Signature> = const { Cell::new(Signature::NONE) };
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Collective {
+    Allreduce = 1,
+    Bcast = 2,
+    Barrier = 3,
+}
+
+/// Arguments of a collective call that must agree across ranks.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Signature {
+    collective: u64,
+    count: u64,
+    datatype: u64,
+    op: u64,
+    root: u64,
+}
+
+impl Signature {
+    const NONE: Self = Signature {
+        collective: 0,
+        count: 0,
+        datatype: 0,
+        op: 0,
+        root: 0,
+    };
+
+    /// Words per rank: the sequence number, then the fields above.
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    /// Signature from fields already encoded as `Display` decodes them.
+    pub(crate) fn encoded(
+        collective: Collective,
+        count: usize,
+        datatype: u64,
+        op: u64,
+        root: Option<ffi::c_int>,
+    ) -> Self {
+        Signature {
+            collective: collective as u64,
+            count: count as u64,
+            datatype,
+            op,
+            root: root.map_or(u64::MAX, |root| root as u64),
+        }
+    }
+
+    /// Signature of a `Group` call, whose elements are only known by size, and
+    /// which only sums.
+    pub fn sized(
+        collective: Collective,
+        count: usize,
+        size: usize,
+        root: Option<ffi::c_int>,
+    ) -> Self {
+        let op = match collective {
+            Collective::Allreduce => 1,
+            Collective::Bcast | Collective::Barrier => 0,
+        };
+        Signature::encoded(collective, count, size as u64, op, root)
+    }
+
+    fn fields(&self) -> [u64; 5] {
+        [
+            self.collective,
+            self.count,
+            self.datatype,
+            self.op,
+            self.root,
+        ]
+    }
+
+    fn from_fields([collective, count, datatype, op, root]: [u64; 5]) -> Self {
+        Signature {
+            collective,
+            count,
+            datatype,
+            op,
+            root,
+        }
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let collective = match self.collective {
+            1 => "MPI_Allreduce",
+            2 => "MPI_Bcast",
+            3 => "barrier",
+            _ => "unknown collective",
+        };
+
+        write!(f, "{}(count={}, datatype=", collective, self.count)?;
+        match DATATYPES.get((self.datatype >> 32) as usize) {
+            Some(name) if self.datatype >> 32 != 0 => write!(f, "{}", name)?,
+            _ => write!(f, "<{} bytes>", self.datatype as u32)?,
+        }
+        match self.op {
+            0 => (),
+            op if op < UNKNOWN_OP => write!(f, ", op={}", OPS[op as usize])?,
+            UNKNOWN_OP => write!(f, ", op=<predefined>")?,
+            op => write!(f, ", op=<user {}>", op - UNKNOWN_OP - 1)?,
+        }
+        if self.root != u64::MAX {
+            write!(f, ", root={}", self.root)?;
+        }
+        write!(f, ")")
+    }
+}
+
+const DATATYPES: [&str; 16] = [
+    "",
+    "MPI_FLOAT",
+    "MPI_DOUBLE",
+    "MPI_INT8_T",
+    "MPI_INT16_T",
+    "MPI_INT32_T",
+    "MPI_INT64_T",
+    "MPI_UINT8_T",
+    "MPI_UINT16_T",
+    "MPI_UINT32_T",
+    "MPI_UINT64_T",
+    "MPI_FLOAT_INT",
+    "MPI_DOUBLE_INT",
+    "MPI_2INT",
+    "MPI_INT",
+    "MPI_SIGNED_CHAR",
+];
+
+const OPS: [&str; 7] = [
+    "",
+    "MPI_SUM",
+    "MPI_MAX",
+    "MPI_MIN",
+    "MPI_PROD",
+    "MPI_MAXLOC",
+    "MPI_MINLOC",
+];
+
+/// Predefined operators missing from `OPS`. User operators follow, by index.
+pub(crate) const UNKNOWN_OP: u64 = OPS.len() as u64;
+
+pub fn enabled() -> bool {
+    *ENABLED
+}
+
+/// Record the signature of the collective being executed.
+pub fn enter(signature: Signature) {
+    CURRENT.with(|current| current.set(signature));
+}
+
+/// Publish this rank's signature for call `sequence` into `lines`, and check
+/// it against every peer's. Requires every rank to have completed the
+/// previous call.
+pub fn verify(lines: &[AtomicU64], sequence: u64, rank: ffi::c_int, total: ffi::c_int) {
+    assert!(
+        total as usize * Signature::WORDS <= lines.len(),
+        "Too many ranks for signature header",
+    );
+
+    let line = |rank: ffi::c_int| &lines[rank as usize * Signature::WORDS..][..Signature::WORDS];
+
+    let signature = CURRENT.with(Cell::get);
+    for (word, field) in line(rank)[1..].iter().zip(signature.fields()) {
+        coherence::store(word, field, Ordering::Relaxed);
+    }
+    coherence::store(&line(rank)[0], sequence + 1, Ordering::Release);
+
+    let signatures = (0..total)
+        .map(|peer| {
+            wait::until(
+                || coherence::load(&line(peer)[0], Ordering::Acquire) == sequence + 1,
+                || wait::Stall {
+                    rank,
+                    word: "call signature",
+                    condition: "==",
+                    expected: sequence + 1,
+                    observed: coherence::load(&line(peer)[0], Ordering::Acquire),
+                    missing: Some(vec![peer]),
+                },
+            );
+
+            let mut fields = [0; 5];
+            for (field, word) in fields.iter_mut().zip(&line(peer)[1..]) {
+                *field = coherence::load(word, Ordering::Relaxed);
+            }
+            Signature::from_fields(fields)
+        })
+        .collect::<Vec<_>>();
+
+    let mismatched = signatures
+        .iter()
+        .enumerate()
+        .filter(|(_, signature)| **signature != signatures[0])
+        .map(|(peer, signature)| format!("rank {} called {}", peer, signature))
+        .collect::<Vec<_>>();
+
+    if !mismatched.is_empty() {
+        crate::abort(format!(
+            "rank {} found mismatched call {}: rank 0 called {}, but {}",
+            rank,
+            sequence,
+            signatures[0],
+            mismatched.join(", "),
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::coherence;
+use crate::lock::Backoff;
+use crate::lock::Lock;
+use crate::metrics;
+use crate::wait;
+use crate::wait::Waiter;
+
+/// Ticket lock, granting the lock in arrival order.
+///
+/// Each rank takes a ticket with `fetch_add` and waits until it is served.
+/// With backoff enabled, waiters back off in proportion to their distance
+/// from the front of the queue.
+///
+/// | Next Ticket         |
+/// | Now Serving, Holder |
+///
+/// The holder is its rank offset by one, with zero meaning unknown, and is
+/// only read to name it on a stall.
+pub struct Ticket<'pci> {
+    lines: &'pci [AtomicU64],
+    rank: ffi::c_int,
+    total: ffi::c_int,
+}
+
+impl<'pci> Ticket<'pci> {
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    fn next(&self) -> &'pci AtomicU64 {
+        &self.lines[0]
+    }
+
+    fn serving(&self) -> &'pci AtomicU64 {
+        &self.lines[Self::WORDS]
+    }
+
+    fn holder(&self) -> &'pci AtomicU64 {
+        &self.lines[Self::WORDS + 1]
+    }
+
+    fn acquired(&self) {
+        coherence::store(self.holder(), self.rank as u64 + 1, Ordering::Relaxed);
+    }
+}
+
+impl<'pci> Lock<'pci> for Ticket<'pci> {
+    fn size(_: ffi::c_int) -> usize {
+        crate::CACHE_LINE_SIZE * 2
+    }
+
+    unsafe fn new(address: *const u8, rank: ffi::c_int, total: ffi::c_int) -> Self {
+        Self {
+            lines: slice::from_raw_parts(address.cast(), Self::WORDS * 2),
+            rank,
+            total,
+        }
+    }
+
+    fn lock(&self) {
+        let ticket = coherence::fetch_add(self.next(), 1, Ordering::AcqRel);
+        let mut waiter = Waiter::new();
+        let serving = coherence::load(self.serving(), Ordering::Acquire);
+
+        // Fast path
+        if serving == ticket {
+            self.acquired();
+            metrics::increment!(metrics::counters::MUTEX_UNCONTENDED);
+            return;
+        }
+
+        let mut backoff = Backoff::new();
+
+        metrics::time!(metrics::timers::MUTEX, {
+            let mut serving = serving;
+            while serving != ticket {
+                backoff.spin_for(ticket.wrapping_sub(serving));
+                waiter.wait(|| wait::Stall {
+                    rank: self.rank,
+                    word: "ticket lock serving",
+                    condition: "==",
+                    expected: ticket,
+                    observed: serving,
+                    missing: coherence::load(self.holder(), Ordering::Relaxed)
+                        .checked_sub(1)
+                        .map(|holder| vec![holder as ffi::c_int]),
+                });
+                serving = coherence::load(self.serving(), Ordering::Acquire);
+            }
+        });
+        self.acquired();
+        metrics::increment!(metrics::counters::MUTEX_CONTENDED);
+    }
+
+    fn unlock(&self) {
+        // Only the lock holder writes `serving` and `holder`
+        let serving = coherence::load(self.serving(), Ordering::Relaxed);
+        coherence::store(self.holder(), 0, Ordering::Relaxed);
+        coherence::store(self.serving(), serving + 1, Ordering::Release);
+        wait::wake_all(self.rank, self.total);
+    }
+}
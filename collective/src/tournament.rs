@@ -0,0 +1,106 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::barrier::Barrier;
+use crate::coherence;
+use crate::metrics;
+use crate::wait;
+
+/// Tournament barrier using only single-writer flags.
+///
+/// In round `k`, rank `i` with `i % 2^(k + 1) == 2^k` loses to rank `i - 2^k`:
+/// it signals its arrival flag and waits to be woken, while the winner waits
+/// for the arrival and advances. Rank 0 wins every round, then wakes the ranks
+/// it beat, each of which wakes the ranks it beat in turn. Every rank loses at
+/// most once, so each flag has exactly one writer.
+///
+/// | Rank 0 Arrival      |
+/// | Rank 0 Wakeup       |
+/// | Rank 1 Arrival      |
+/// | ...                 |
+pub struct Tournament<'pci>(&'pci [AtomicU64]);
+
+impl<'pci> Tournament<'pci> {
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    /// Requires first `Barrier::SIZE` bytes to be zero-initialized.
+    pub unsafe fn new(address: *const u8) -> Self {
+        Self(slice::from_raw_parts(
+            address.cast(),
+            Barrier::SIZE / mem::size_of::<AtomicU64>(),
+        ))
+    }
+
+    pub fn wait(&self, rank: ffi::c_int, total: ffi::c_int) {
+        static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+        let epoch = EPOCH.load(Ordering::Acquire) + 1;
+        let rank = rank as usize;
+        let total = total as usize;
+
+        // Distance to the rank that beats this one, or `total` for the champion
+        let mut distance = 1;
+
+        metrics::time!(metrics::timers::BARRIER, {
+            while distance < total && rank.is_multiple_of(distance * 2) {
+                let loser = rank + distance;
+                if loser < total {
+                    // Wait for loser to arrive
+                    wait::until(
+                        || coherence::load(self.arrival(loser), Ordering::Acquire) >= epoch,
+                        || wait::Stall {
+                            rank: rank as ffi::c_int,
+                            word: "tournament barrier arrival",
+                            condition: ">=",
+                            expected: epoch,
+                            observed: coherence::load(self.arrival(loser), Ordering::Acquire),
+                            missing: Some(vec![loser as ffi::c_int]),
+                        },
+                    );
+                }
+                distance *= 2;
+            }
+
+            if distance < total {
+                coherence::store(self.arrival(rank), epoch, Ordering::Release);
+                wait::wake((rank - distance) as ffi::c_int);
+
+                // Wait for winner to wake this rank
+                wait::until(
+                    || coherence::load(self.wakeup(rank), Ordering::Acquire) >= epoch,
+                    || wait::Stall {
+                        rank: rank as ffi::c_int,
+                        word: "tournament barrier wakeup",
+                        condition: ">=",
+                        expected: epoch,
+                        observed: coherence::load(self.wakeup(rank), Ordering::Acquire),
+                        missing: Some(vec![(rank - distance) as ffi::c_int]),
+                    },
+                );
+            }
+        });
+
+        // Wake ranks beaten by this one, latest round first
+        while distance > 1 {
+            distance /= 2;
+            let loser = rank + distance;
+            if loser < total {
+                coherence::store(self.wakeup(loser), epoch, Ordering::Release);
+                wait::wake(loser as ffi::c_int);
+            }
+        }
+
+        EPOCH.store(epoch, Ordering::Release);
+    }
+
+    fn arrival(&self, rank: usize) -> &'pci AtomicU64 {
+        &self.0[rank * 2 * Self::WORDS]
+    }
+
+    fn wakeup(&self, rank: usize) -> &'pci AtomicU64 {
+        &self.0[(rank * 2 + 1) * Self::WORDS]
+    }
+}
@@ -0,0 +1,106 @@
+use std::ffi;
+use std::mem;
+use std::slice;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::barrier::Arrivals;
+use crate::barrier::Barrier;
+use crate::coherence;
+use crate::metrics;
+use crate::wait;
+
+/// Combining tree barrier.
+///
+/// Ranks arrive in groups of `ARITY` at a leaf counter, and the last to arrive
+/// at each node continues to its parent, so no counter sees more than `ARITY`
+/// concurrent `fetch_add`s. The last rank to arrive at the root releases
+/// everyone through a separate flag.
+///
+/// | Release             |
+/// | Level 0 Node 0      |
+/// | Level 0 Node 1      |
+/// | ...                 |
+/// | Level 1 Node 0      |
+/// | ...                 |
+/// | Arrivals            |
+pub struct Tree<'pci> {
+    lines: &'pci [AtomicU64],
+    arrivals: Arrivals<'pci>,
+}
+
+impl<'pci> Tree<'pci> {
+    const ARITY: usize = 4;
+    const WORDS: usize = crate::CACHE_LINE_SIZE / mem::size_of::<AtomicU64>();
+
+    /// Requires first `Barrier::SIZE` bytes to be zero-initialized.
+    pub unsafe fn new(address: *const u8) -> Self {
+        // Nodes in the first half, arrivals in the second
+        Self {
+            lines: slice::from_raw_parts(
+                address.cast(),
+                Barrier::SIZE / 2 / mem::size_of::<AtomicU64>(),
+            ),
+            arrivals: Arrivals::new(address),
+        }
+    }
+
+    pub fn wait(&self, rank: ffi::c_int, total: ffi::c_int) {
+        static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+        let epoch = EPOCH.load(Ordering::Acquire) + 1;
+        self.arrivals.arrive(rank, epoch);
+
+        // Index of this rank's node within the current level
+        let mut index = rank as usize / Self::ARITY;
+
+        // Number of arrivals (ranks or child nodes) in the current level
+        let mut width = total as usize;
+
+        // Line of the first node in the current level
+        let mut base = 1;
+
+        let last = loop {
+            let fan_in = Self::ARITY.min(width - index * Self::ARITY);
+            let node = self.line(base + index);
+
+            if coherence::fetch_add(node, 1, Ordering::AcqRel) + 1 < epoch * fan_in as u64 {
+                break false;
+            }
+
+            if width <= Self::ARITY {
+                break true;
+            }
+
+            base += width.div_ceil(Self::ARITY);
+            width = width.div_ceil(Self::ARITY);
+            index /= Self::ARITY;
+        };
+
+        if last {
+            coherence::store(self.line(0), epoch, Ordering::Release);
+            wait::wake_all(rank, total);
+        } else {
+            metrics::time!(metrics::timers::BARRIER, {
+                // Wait for root to release
+                wait::until(
+                    || coherence::load(self.line(0), Ordering::Acquire) >= epoch,
+                    || wait::Stall {
+                        rank,
+                        word: "tree barrier release",
+                        condition: ">=",
+                        expected: epoch,
+                        observed: coherence::load(self.line(0), Ordering::Acquire),
+                        missing: Some(self.arrivals.missing(total, epoch)),
+                    },
+                );
+            });
+        }
+
+        EPOCH.store(epoch, Ordering::Release);
+    }
+
+    fn line(&self, line: usize) -> &'pci AtomicU64 {
+        &self.lines[line * Self::WORDS]
+    }
+}
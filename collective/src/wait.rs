@@ -0,0 +1,259 @@
+//! Strategies for waiting on shared memory, selected by
+//! `COLLECTIVE_WAIT_STRATEGY`:
+//!
+//! - `spin`: poll back-to-back.
+//! - `pause`: poll with `core::hint::spin_loop` in between.
+//! - `yield`: pause for `COLLECTIVE_WAIT_SPINS` polls, then `sched_yield`.
+//! - `sleep`: pause for `COLLECTIVE_WAIT_SPINS` polls, then sleep for
+//!   `COLLECTIVE_WAIT_SLEEP_NS` nanoseconds.
+//! - `notify`: pause for `COLLECTIVE_WAIT_SPINS` polls, then block until a
+//!   peer notifies this rank through the backend selected by
+//!   `COLLECTIVE_NOTIFIER` (see `notifier`). `doorbell` is accepted as an
+//!   alias.
+//!
+//! Defaults to `notify` with `feature = "interrupts"`, and `spin` otherwise.
+//!
+//! If `COLLECTIVE_WAIT_TIMEOUT_MS` is set, a wait that makes no progress for
+//! that long reports what it was waiting on and aborts the job with
+//! `MPI_Abort`, counting from the start of the wait. Waits also abort early
+//! if `liveness` finds a peer gone.
+
+use std::cell::Cell;
+use std::env;
+use std::ffi;
+use std::hint;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+
+use crate::liveness;
+use crate::notifier::NOTIFIER;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Strategy {
+    Spin,
+    Pause,
+    Yield,
+    Sleep,
+    Notify,
+}
+
+static STRATEGY: Lazy<Strategy> =
+    Lazy::new(|| match env::var("COLLECTIVE_WAIT_STRATEGY").as_deref() {
+        Ok("spin") => Strategy::Spin,
+        Ok("pause") => Strategy::Pause,
+        Ok("yield") => Strategy::Yield,
+        Ok("sleep") => Strategy::Sleep,
+        Ok("notify") | Ok("doorbell") => Strategy::Notify,
+        #[cfg(feature = "interrupts")]
+        Err(_) => Strategy::Notify,
+        #[cfg(not(feature = "interrupts"))]
+        Err(_) => Strategy::Spin,
+        Ok(strategy) => panic!("Unknown wait strategy: {}", strategy),
+    });
+
+static SPINS: Lazy<u64> = Lazy::new(|| match env::var("COLLECTIVE_WAIT_SPINS") {
+    Err(_) => 1000,
+    Ok(spins) => spins
+        .parse::<u64>()
+        .expect("Failed to parse COLLECTIVE_WAIT_SPINS as u64"),
+});
+
+static SLEEP: Lazy<Duration> = Lazy::new(|| match env::var("COLLECTIVE_WAIT_SLEEP_NS") {
+    Err(_) => Duration::from_micros(1),
+    Ok(sleep) => sleep
+        .parse::<u64>()
+        .map(Duration::from_nanos)
+        .expect("Failed to parse COLLECTIVE_WAIT_SLEEP_NS as u64"),
+});
+
+static TIMEOUT: Lazy<Option<Duration>> =
+    Lazy::new(|| match env::var("COLLECTIVE_WAIT_TIMEOUT_MS") {
+        Err(_) => None,
+        Ok(timeout) => timeout
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .map(Some)
+            .expect("Failed to parse COLLECTIVE_WAIT_TIMEOUT_MS as u64"),
+    });
+
+/// Polls between timeout checks while spinning.
+const CHECK_INTERVAL: u64 = 1024;
+
+thread_local! {
+    static COLLECTIVE: Cell<&'static str> = const { Cell::new("unknown collective") };
+}
+
+pub fn initialize() {
+    Lazy::force(&STRATEGY);
+    Lazy::force(&SPINS);
+    Lazy::force(&SLEEP);
+    Lazy::force(&TIMEOUT);
+    if *STRATEGY == Strategy::Notify {
+        Lazy::force(&NOTIFIER);
+    }
+}
+
+/// Name the collective being executed, for hang diagnostics.
+pub fn enter(collective: &'static str) {
+    COLLECTIVE.with(|current| current.set(collective));
+}
+
+/// What a wait is blocked on, reported if it times out.
+pub struct Stall {
+    /// Rank that is waiting.
+    pub rank: ffi::c_int,
+    /// Shared word being polled, e.g. `"dissemination barrier flag"`.
+    pub word: &'static str,
+    /// Comparison the wait is polling for, e.g. `">="`.
+    pub condition: &'static str,
+    pub expected: u64,
+    pub observed: u64,
+    /// Peers that have not arrived, if known.
+    pub missing: Option<Vec<ffi::c_int>>,
+}
+
+/// Tracks polls of a single wait, to escalate from spinning.
+pub struct Waiter {
+    polls: u64,
+    token: u32,
+    start: Instant,
+    checked: Option<Instant>,
+}
+
+impl Waiter {
+    /// Must be created before the first poll.
+    pub fn new() -> Self {
+        Waiter {
+            polls: 0,
+            token: prepare(),
+            start: Instant::now(),
+            checked: None,
+        }
+    }
+
+    /// Called after each unsuccessful poll, with a description of the wait
+    /// in case it has timed out or a peer is gone.
+    pub fn wait<S: Fn() -> Stall>(&mut self, stall: S) {
+        self.polls += 1;
+
+        // Longest to block for before checking again
+        let mut remaining = None;
+
+        let interval = liveness::interval();
+        if (TIMEOUT.is_some() || interval.is_some())
+            && (self.polls.is_multiple_of(CHECK_INTERVAL) || self.polls > *SPINS)
+        {
+            let elapsed = self.start.elapsed();
+
+            if let Some(timeout) = *TIMEOUT {
+                if elapsed >= timeout {
+                    expire(stall(), format!("timed out after {:?}", elapsed));
+                }
+                remaining = Some(timeout - elapsed);
+            }
+
+            if let Some(interval) = interval {
+                let due = match self.checked {
+                    None => true,
+                    Some(checked) => checked.elapsed() >= interval,
+                };
+
+                if due {
+                    self.checked = Some(Instant::now());
+                    let stall = stall();
+                    if let Some((rank, reason)) = liveness::check(stall.missing.as_deref()) {
+                        let cause =
+                            format!("found rank {} gone ({}) after {:?}", rank, reason, elapsed);
+                        expire(stall, cause);
+                    }
+                }
+
+                remaining = Some(remaining.map_or(interval, |remaining| remaining.min(interval)));
+            }
+        }
+
+        match *STRATEGY {
+            Strategy::Spin => (),
+            Strategy::Pause => hint::spin_loop(),
+            _ if self.polls <= *SPINS => hint::spin_loop(),
+            Strategy::Yield => unsafe {
+                libc::sched_yield();
+            },
+            Strategy::Sleep => thread::sleep(*SLEEP),
+            Strategy::Notify => {
+                NOTIFIER.wait(self.token, remaining);
+                self.token = prepare();
+            }
+        }
+    }
+}
+
+/// Wait until `ready` returns true.
+pub fn until<F: FnMut() -> bool, S: Fn() -> Stall>(mut ready: F, stall: S) {
+    let mut waiter = Waiter::new();
+    while !ready() {
+        waiter.wait(&stall);
+    }
+}
+
+/// Wake `rank` if it is blocked waiting.
+pub fn wake(rank: ffi::c_int) {
+    if *STRATEGY == Strategy::Notify {
+        NOTIFIER.notify(rank);
+    }
+}
+
+/// Wake every rank except `exclude` if they are blocked waiting.
+pub fn wake_all(exclude: ffi::c_int, total: ffi::c_int) {
+    if *STRATEGY == Strategy::Notify {
+        NOTIFIER.notify_all(exclude, total);
+    }
+}
+
+/// Stop notifying through shared memory, before it is unmapped.
+#[cfg(feature = "mpi")]
+pub fn stop() {
+    // Never initialize the notifier here, which would lock the map
+    if let Some(notifier) = Lazy::get(&NOTIFIER) {
+        notifier.stop();
+    }
+}
+
+fn prepare() -> u32 {
+    match *STRATEGY {
+        Strategy::Notify => NOTIFIER.prepare(),
+        _ => 0,
+    }
+}
+
+fn expire(stall: Stall, cause: String) -> ! {
+    let missing = match stall.missing {
+        Some(missing) => {
+            let missing = missing
+                .into_iter()
+                .map(|rank| match liveness::describe(rank) {
+                    Some(description) => format!("{} ({})", rank, description),
+                    None => rank.to_string(),
+                })
+                .collect::<Vec<_>>();
+            format!("[{}]", missing.join(", "))
+        }
+        None => String::from("unknown"),
+    };
+
+    crate::abort(format!(
+        "rank {} {} in {} waiting on {}: \
+         expected {} {}, observed {}; peers not arrived: {}",
+        stall.rank,
+        cause,
+        COLLECTIVE.with(Cell::get),
+        stall.word,
+        stall.condition,
+        stall.expected,
+        stall.observed,
+        missing,
+    ))
+}
@@ -0,0 +1,121 @@
+// Runs the C API across forked ranks sharing a file in /dev/shm, as built and
+// run by `capi.sh` against the generated header and library.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include <collective.h>
+
+#define RANKS 3
+#define SIZE (16 << 20)
+#define PATH "/dev/shm/collective-test-capi"
+
+#define CHECK(condition)                                                       \
+  do {                                                                         \
+    if (!(condition)) {                                                        \
+      fprintf(stderr, "rank %d: %s:%d: %s (last error: %s)\n", rank, __FILE__, \
+              __LINE__, #condition, collective_last_error());                  \
+      exit(1);                                                                 \
+    }                                                                          \
+  } while (0)
+
+static void run(int rank) {
+  CHECK(collective_init("/nonexistent/collective", SIZE, rank, RANKS) ==
+        COLLECTIVE_ERROR_OPEN);
+  CHECK(strlen(collective_last_error()) > 0);
+  CHECK(collective_init(PATH, SIZE, RANKS, RANKS) == COLLECTIVE_ERROR_ARGUMENT);
+
+  CHECK(collective_barrier() == COLLECTIVE_ERROR_UNINITIALIZED);
+  CHECK(collective_init(PATH, SIZE, rank, RANKS) == COLLECTIVE_SUCCESS);
+  CHECK(collective_init(PATH, SIZE, rank, RANKS) ==
+        COLLECTIVE_ERROR_INITIALIZED);
+
+  char bytes[1000];
+  for (int i = 0; i < 1000; i++) {
+    bytes[i] = rank == 1 ? (char)i : 0;
+  }
+  CHECK(collective_bcast(bytes, 1000, COLLECTIVE_INT8, 1) ==
+        COLLECTIVE_SUCCESS);
+  for (int i = 0; i < 1000; i++) {
+    CHECK(bytes[i] == (char)i);
+  }
+
+  // In place
+  float floats[5000];
+  for (int i = 0; i < 5000; i++) {
+    floats[i] = (float)(rank + i);
+  }
+  CHECK(collective_allreduce(floats, floats, 5000, COLLECTIVE_FLOAT) ==
+        COLLECTIVE_SUCCESS);
+  for (int i = 0; i < 5000; i++) {
+    CHECK(floats[i] == (float)(RANKS * (RANKS - 1) / 2 + RANKS * i));
+  }
+
+  int send[100], receive[100];
+  for (int i = 0; i < 100; i++) {
+    send[i] = rank * i;
+  }
+  CHECK(collective_allreduce(send, receive, 100, COLLECTIVE_INT32) ==
+        COLLECTIVE_SUCCESS);
+  for (int i = 0; i < 100; i++) {
+    CHECK(receive[i] == RANKS * (RANKS - 1) / 2 * i);
+  }
+
+  // Rejected before any rank takes a slot, so ranks stay in step
+  CHECK(collective_allreduce(send, send + 1, 99, COLLECTIVE_INT32) ==
+        COLLECTIVE_ERROR_ARGUMENT);
+  CHECK(collective_bcast(send, 1, 42, 0) == COLLECTIVE_ERROR_ARGUMENT);
+  CHECK(collective_bcast(send, 1, COLLECTIVE_INT32, RANKS) ==
+        COLLECTIVE_ERROR_ARGUMENT);
+
+  size_t count = SIZE / sizeof(double);
+  double *large = calloc(count, sizeof(double));
+  CHECK(large != NULL);
+  CHECK(collective_allreduce(large, large, count, COLLECTIVE_DOUBLE) ==
+        COLLECTIVE_ERROR_SIZE);
+  CHECK(strstr(collective_last_error(), "allreduce") != NULL);
+  CHECK(collective_bcast(large, count, COLLECTIVE_DOUBLE, 0) ==
+        COLLECTIVE_ERROR_SIZE);
+  free(large);
+
+  CHECK(collective_barrier() == COLLECTIVE_SUCCESS);
+  CHECK(collective_finalize() == COLLECTIVE_SUCCESS);
+  CHECK(collective_finalize() == COLLECTIVE_ERROR_UNINITIALIZED);
+}
+
+int main(void) {
+  // The default doorbell notifier would write into the file
+  setenv("COLLECTIVE_NOTIFIER", "futex", 1);
+
+  FILE *file = fopen(PATH, "w");
+  if (file == NULL || ftruncate(fileno(file), SIZE) != 0) {
+    perror(PATH);
+    return 1;
+  }
+  fclose(file);
+
+  pid_t children[RANKS];
+  for (int rank = 0; rank < RANKS; rank++) {
+    children[rank] = fork();
+    if (children[rank] == 0) {
+      run(rank);
+      exit(0);
+    }
+  }
+
+  int failed = 0;
+  for (int rank = 0; rank < RANKS; rank++) {
+    int status;
+    waitpid(children[rank], &status, 0);
+    if (!WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+      fprintf(stderr, "rank %d failed\n", rank);
+      failed = 1;
+    }
+  }
+
+  unlink(PATH);
+  return failed;
+}
@@ -0,0 +1,17 @@
+#!/usr/bin/env bash
+# Build the C API library without MPI, as `make install` does, and run
+# `capi.c` against it and its generated header.
+set -euxo pipefail
+
+tests="$(cd "$(dirname "$0")" && pwd)"
+work="$(mktemp -d)"
+trap 'rm -rf "$work"' EXIT
+
+messages="$(cargo build --manifest-path "$tests/../Cargo.toml" \
+  --no-default-features --message-format=json)"
+out_dir="$(echo "$messages" | sed -n 's|.*"out_dir":"\([^"]*/build/collective-[0-9a-f]*/out\)".*|\1|p')"
+library="$(echo "$messages" | sed -n 's|.*"\([^"]*/libcollective\.so\)".*|\1|p')"
+
+${CC:-cc} -Wall -Werror -o "$work/capi" "$tests/capi.c" \
+  -I"$out_dir" -L"$(dirname "$library")" -lcollective
+LD_LIBRARY_PATH="$(dirname "$library")" "$work/capi"
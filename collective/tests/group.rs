@@ -0,0 +1,154 @@
+//! Multi-process tests of `Group`. Each test re-runs itself as one child
+//! process per rank, which opens a shared file in `/dev/shm` and runs the
+//! test body with its rank.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use collective::Config;
+use collective::Group;
+
+const RANKS: usize = 4;
+const SIZE: usize = 16 << 20;
+
+/// Run `body` on `RANKS` ranks over `path`, as children re-running test
+/// `name`, and assert that every rank succeeded. Returns `true` in the parent,
+/// and `false` in a child once `body` has run.
+fn spawn(name: &str, path: &Path, body: fn(&Group)) -> bool {
+    if let Ok(rank) = env::var("COLLECTIVE_TEST_RANK") {
+        let group = Group::new(&Config {
+            path: path.to_owned(),
+            size: SIZE,
+            rank: rank.parse().unwrap(),
+            ranks: RANKS,
+        })
+        .unwrap();
+        body(&group);
+        group.finalize();
+        return false;
+    }
+
+    let children = (0..RANKS)
+        .map(|rank| {
+            Command::new(env::current_exe().unwrap())
+                .args([name, "--exact", "--nocapture"])
+                .env("COLLECTIVE_TEST_RANK", rank.to_string())
+                // The default doorbell would write into the file
+                .env("COLLECTIVE_NOTIFIER", "futex")
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    for (rank, mut child) in children.into_iter().enumerate() {
+        assert!(child.wait().unwrap().success(), "rank {} failed", rank);
+    }
+    true
+}
+
+/// Zeroed file shared by one test's ranks, removed by the parent.
+struct Shared(PathBuf);
+
+impl Shared {
+    fn new(name: &str) -> Self {
+        let path = PathBuf::from(format!("/dev/shm/collective-test-{}", name));
+        if env::var("COLLECTIVE_TEST_RANK").is_err() {
+            let file = fs::File::create(&path).unwrap();
+            file.set_len(SIZE as u64).unwrap();
+        }
+        Shared(path)
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if env::var("COLLECTIVE_TEST_RANK").is_err() {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+fn sums(group: &Group) {
+    for len in [1, 1000, 4099, 100_000] {
+        let send = (0..len)
+            .map(|index| (index * (group.rank() + 1)) as f64)
+            .collect::<Vec<_>>();
+        let mut receive = vec![0.0; len];
+        group.allreduce(&send, &mut receive).unwrap();
+
+        let ranks = (1..=group.size()).sum::<usize>();
+        for (index, value) in receive.iter().enumerate() {
+            assert_eq!(*value, (index * ranks) as f64);
+        }
+
+        let send = vec![group.rank() as i32 - 1; len];
+        let mut receive = vec![0; len];
+        group.allreduce(&send, &mut receive).unwrap();
+        let expected = (0..group.size() as i32).map(|rank| rank - 1).sum::<i32>();
+        assert!(receive.iter().all(|value| *value == expected));
+    }
+}
+
+fn broadcasts(group: &Group) {
+    for (root, len) in [(0, 1), (1, 1000), (2, 4099), (3, 100_000)] {
+        let mut data = match group.rank() == root {
+            true => (0..len).map(|index| index as f32 + 0.5).collect(),
+            false => vec![0.0; len],
+        };
+        group.broadcast(&mut data, root).unwrap();
+
+        for (index, value) in data.iter().enumerate() {
+            assert_eq!(*value, index as f32 + 0.5);
+        }
+    }
+}
+
+fn rejects(group: &Group) {
+    // Half the file is more than one slot
+    let len = SIZE / 2 / std::mem::size_of::<f32>();
+    let send = vec![1.0_f32; len];
+    let mut receive = vec![0.0; len];
+    assert!(group.allreduce(&send, &mut receive).is_err());
+
+    let mut data = vec![0_i8; SIZE / 2];
+    assert!(group.broadcast(&mut data, 0).is_err());
+
+    // Rejected calls take no slot, so ranks stay in step
+    let mut receive = [0.0_f32];
+    group.allreduce(&[1.0], &mut receive).unwrap();
+    assert_eq!(receive[0], group.size() as f32);
+}
+
+#[test]
+fn allreduce() {
+    let shared = Shared::new("allreduce");
+    spawn("allreduce", &shared.0, sums);
+}
+
+#[test]
+fn broadcast() {
+    let shared = Shared::new("broadcast");
+    spawn("broadcast", &shared.0, broadcasts);
+}
+
+#[test]
+fn oversized() {
+    let shared = Shared::new("oversized");
+    spawn("oversized", &shared.0, rejects);
+}
+
+#[test]
+fn reuse() {
+    let shared = Shared::new("reuse");
+
+    // The second group relies on the first zeroing the header as it leaves,
+    // and its children return from the first call
+    if spawn("reuse", &shared.0, sums) {
+        spawn("reuse", &shared.0, sums);
+    }
+}
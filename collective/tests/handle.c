@@ -0,0 +1,16 @@
+// Predefined handles as `mpi.h` defines them, for `handle.rs` to compare its
+// own against. Built with each MPI's `mpicc` by `mpi.sh`.
+
+#include <stddef.h>
+
+#include <mpi.h>
+
+void collective_test_handles(size_t *handles) {
+  handles[0] = (size_t)MPI_INT;
+  handles[1] = (size_t)MPI_SIGNED_CHAR;
+  handles[2] = (size_t)MPI_FLOAT_INT;
+  handles[3] = (size_t)MPI_DOUBLE_INT;
+  handles[4] = (size_t)MPI_2INT;
+  handles[5] = (size_t)MPI_MAXLOC;
+  handles[6] = (size_t)MPI_MINLOC;
+}
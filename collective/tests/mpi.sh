@@ -0,0 +1,35 @@
+#!/usr/bin/env bash
+# Run the tests that need an MPI runtime under each MPI given, as the directory
+# holding its `mpicc` and `mpirun`, e.g.
+#
+#   collective/tests/mpi.sh /usr/lib64/mpich/bin /usr/lib64/openmpi/bin
+#
+# Each MPI gets its own target directory, since mpi-sys links the one found at
+# build time.
+set -euxo pipefail
+
+tests="$(cd "$(dirname "$0")" && pwd)"
+work="$(mktemp -d)"
+trap 'rm -rf "$work"' EXIT
+
+for bin in "$@"; do
+  (
+    export PATH="$bin:$PATH"
+    export LD_LIBRARY_PATH="$bin/../lib${LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}"
+    out="$(mktemp -d -p "$work")"
+
+    mpicc -shared -fPIC -o "$out/libhandle.so" "$tests/handle.c"
+    binary="$(CARGO_TARGET_DIR="$out/target" cargo test \
+      --manifest-path "$tests/../Cargo.toml" --lib --no-run --message-format=json \
+      | sed -n 's|.*"executable":"\([^"]*\)".*|\1|p')"
+
+    # One job per test, as each initializes and finalizes MPI
+    truncate -s 64M "$out/pci"
+    for test in $("$binary" --list --ignored --format=terse | sed -n 's|^\(interpose::.*\): test$|\1|p'); do
+      COLLECTIVE_TEST_HANDLES="$out/libhandle.so" \
+        COLLECTIVE_PCI_PATH="$out/pci" \
+        COLLECTIVE_PCI_SIZE=$((64 << 20)) \
+        mpirun -n 2 "$binary" --ignored --exact "$test"
+    done
+  )
+done